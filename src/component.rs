@@ -0,0 +1,436 @@
+//! Helpers for reading common properties from any kind of component, without
+//! having to match every variant of `SAPComponent` at each use.
+
+use sap_scripting::{
+    GuiComboBox_Impl, GuiComponentCollection, GuiComponentCollection_Impl, GuiComponent_Impl,
+    GuiContainer_Impl, GuiFrameWindow_Impl, GuiSession, GuiTextField_Impl, GuiVComponent_Impl,
+    GuiVContainer_Impl, SAPComponent,
+};
+
+/// Match a component against every variant that implements `GuiComponent`,
+/// binding it to `$c` and evaluating to `Some($body)`, or `None` for any
+/// other variant.
+macro_rules! with_component {
+    ($comp:expr, $c:ident => $body:expr) => {
+        match $comp {
+            SAPComponent::GuiApplication($c) => Some($body),
+            SAPComponent::GuiComponent($c) => Some($body),
+            SAPComponent::GuiContainer($c) => Some($body),
+            other => $crate::component::with_vcomponent!(other, $c => $body),
+        }
+    };
+}
+
+/// Match a component against every variant that implements `GuiVComponent`,
+/// binding it to `$c` and evaluating to `Some($body)`, or `None` for any
+/// other variant.
+macro_rules! with_vcomponent {
+    ($comp:expr, $c:ident => $body:expr) => {
+        match $comp {
+            SAPComponent::GuiBarChart($c) => Some($body),
+            SAPComponent::GuiBox($c) => Some($body),
+            SAPComponent::GuiButton($c) => Some($body),
+            SAPComponent::GuiCalendar($c) => Some($body),
+            SAPComponent::GuiChart($c) => Some($body),
+            SAPComponent::GuiCheckBox($c) => Some($body),
+            SAPComponent::GuiColorSelector($c) => Some($body),
+            SAPComponent::GuiComboBox($c) => Some($body),
+            SAPComponent::GuiComboBoxControl($c) => Some($body),
+            SAPComponent::GuiContainerShell($c) => Some($body),
+            SAPComponent::GuiCTextField($c) => Some($body),
+            SAPComponent::GuiCustomControl($c) => Some($body),
+            SAPComponent::GuiDialogShell($c) => Some($body),
+            SAPComponent::GuiEAIViewer2D($c) => Some($body),
+            SAPComponent::GuiEAIViewer3D($c) => Some($body),
+            SAPComponent::GuiFrameWindow($c) => Some($body),
+            SAPComponent::GuiGOSShell($c) => Some($body),
+            SAPComponent::GuiGraphAdapt($c) => Some($body),
+            SAPComponent::GuiGridView($c) => Some($body),
+            SAPComponent::GuiHTMLViewer($c) => Some($body),
+            SAPComponent::GuiInputFieldControl($c) => Some($body),
+            SAPComponent::GuiLabel($c) => Some($body),
+            SAPComponent::GuiMainWindow($c) => Some($body),
+            SAPComponent::GuiMap($c) => Some($body),
+            SAPComponent::GuiMenu($c) => Some($body),
+            SAPComponent::GuiMenubar($c) => Some($body),
+            SAPComponent::GuiModalWindow($c) => Some($body),
+            SAPComponent::GuiNetChart($c) => Some($body),
+            SAPComponent::GuiOfficeIntegration($c) => Some($body),
+            SAPComponent::GuiOkCodeField($c) => Some($body),
+            SAPComponent::GuiPasswordField($c) => Some($body),
+            SAPComponent::GuiPicture($c) => Some($body),
+            SAPComponent::GuiRadioButton($c) => Some($body),
+            SAPComponent::GuiSapChart($c) => Some($body),
+            SAPComponent::GuiScrollContainer($c) => Some($body),
+            SAPComponent::GuiShell($c) => Some($body),
+            SAPComponent::GuiSimpleContainer($c) => Some($body),
+            SAPComponent::GuiSplit($c) => Some($body),
+            SAPComponent::GuiSplitterContainer($c) => Some($body),
+            SAPComponent::GuiStage($c) => Some($body),
+            SAPComponent::GuiStatusbar($c) => Some($body),
+            SAPComponent::GuiStatusPane($c) => Some($body),
+            SAPComponent::GuiTab($c) => Some($body),
+            SAPComponent::GuiTableControl($c) => Some($body),
+            SAPComponent::GuiTabStrip($c) => Some($body),
+            SAPComponent::GuiTextedit($c) => Some($body),
+            SAPComponent::GuiTextField($c) => Some($body),
+            SAPComponent::GuiTitlebar($c) => Some($body),
+            SAPComponent::GuiToolbar($c) => Some($body),
+            SAPComponent::GuiTree($c) => Some($body),
+            SAPComponent::GuiUserArea($c) => Some($body),
+            SAPComponent::GuiVComponent($c) => Some($body),
+            SAPComponent::GuiVContainer($c) => Some($body),
+            SAPComponent::GuiVHViewSwitch($c) => Some($body),
+            _ => None,
+        }
+    };
+}
+
+/// Match a component against every variant that can contain other
+/// components, binding it to `$c` and evaluating to `Some($body)`, or `None`
+/// for any other variant.
+macro_rules! with_container {
+    ($comp:expr, $c:ident => $body:expr) => {
+        match $comp {
+            SAPComponent::GuiContainer($c) => Some($body),
+            SAPComponent::GuiContainerShell($c) => Some($body),
+            SAPComponent::GuiCustomControl($c) => Some($body),
+            SAPComponent::GuiDialogShell($c) => Some($body),
+            SAPComponent::GuiFrameWindow($c) => Some($body),
+            SAPComponent::GuiGOSShell($c) => Some($body),
+            SAPComponent::GuiMainWindow($c) => Some($body),
+            SAPComponent::GuiMenu($c) => Some($body),
+            SAPComponent::GuiMenubar($c) => Some($body),
+            SAPComponent::GuiModalWindow($c) => Some($body),
+            SAPComponent::GuiScrollContainer($c) => Some($body),
+            SAPComponent::GuiSimpleContainer($c) => Some($body),
+            SAPComponent::GuiSplitterContainer($c) => Some($body),
+            SAPComponent::GuiStatusbar($c) => Some($body),
+            SAPComponent::GuiTab($c) => Some($body),
+            SAPComponent::GuiTableControl($c) => Some($body),
+            SAPComponent::GuiTabStrip($c) => Some($body),
+            SAPComponent::GuiTitlebar($c) => Some($body),
+            SAPComponent::GuiToolbar($c) => Some($body),
+            SAPComponent::GuiUserArea($c) => Some($body),
+            SAPComponent::GuiVContainer($c) => Some($body),
+            _ => None,
+        }
+    };
+}
+
+pub(crate) use with_vcomponent;
+
+/// The position and size of a component on the screen, in pixels.
+#[derive(Clone, Copy)]
+pub(crate) struct Bounds {
+    pub(crate) left: i32,
+    pub(crate) top: i32,
+    pub(crate) width: i32,
+    pub(crate) height: i32,
+}
+
+impl Bounds {
+    /// Whether another component sits on the same row as this one, i.e. its
+    /// vertical centre falls within this component.
+    pub(crate) fn same_row(&self, other: &Bounds) -> bool {
+        let centre = other.top + other.height / 2;
+        centre >= self.top && centre <= self.top + self.height
+    }
+}
+
+/// The ID of a component.
+pub(crate) fn id(comp: &SAPComponent) -> Option<String> {
+    with_component!(comp, c => c.id().ok()).flatten()
+}
+
+/// The type of a component, e.g. `GuiButton`.
+pub(crate) fn type_name(comp: &SAPComponent) -> Option<String> {
+    with_component!(comp, c => c._type().ok()).flatten()
+}
+
+/// The name of a component, i.e. the last part of its ID without the prefix.
+pub(crate) fn name(comp: &SAPComponent) -> Option<String> {
+    with_component!(comp, c => c.name().ok()).flatten()
+}
+
+/// The text shown by a visual component.
+pub(crate) fn text(comp: &SAPComponent) -> Option<String> {
+    with_vcomponent!(comp, c => c.text().ok()).flatten()
+}
+
+/// Get the text of a component, failing clearly if it has no text.
+pub(crate) fn get_text(comp: &SAPComponent) -> Result<String, String> {
+    with_vcomponent!(comp, c => c.text())
+        .ok_or_else(|| no_text(comp))?
+        .map_err(|e| format!("Can't get text: {e}"))
+}
+
+/// Set the text of a component, failing clearly if it has no text or is
+/// read-only.
+pub(crate) fn set_text(comp: &SAPComponent, value: String) -> Result<(), String> {
+    let changeable = with_vcomponent!(comp, c => c.changeable())
+        .ok_or_else(|| no_text(comp))?
+        .map_err(|e| format!("Can't tell whether the target can be changed: {e}"))?;
+    if !changeable {
+        return Err(format!(
+            "{} is read-only, so its text can't be set.",
+            id(comp).unwrap_or_else(|| String::from("The target"))
+        ));
+    }
+    with_vcomponent!(comp, c => c.set_text(value))
+        .ok_or_else(|| no_text(comp))?
+        .map_err(|e| format!("Can't set text: {e}"))
+}
+
+/// The error for a component that has no text.
+fn no_text(comp: &SAPComponent) -> String {
+    match type_name(comp) {
+        Some(component_type) => format!("{component_type} components have no text."),
+        None => String::from("The target has no text."),
+    }
+}
+
+/// The tooltip of a visual component.
+pub(crate) fn tooltip(comp: &SAPComponent) -> Option<String> {
+    with_vcomponent!(comp, c => c.tooltip().ok()).flatten()
+}
+
+/// Whether a visual component can be changed.
+pub(crate) fn changeable(comp: &SAPComponent) -> Option<bool> {
+    with_vcomponent!(comp, c => c.changeable().ok()).flatten()
+}
+
+/// The position and size of a visual component on the screen.
+pub(crate) fn bounds(comp: &SAPComponent) -> Option<Bounds> {
+    with_vcomponent!(comp, c => {
+        Some(Bounds {
+            left: c.screen_left().ok()?,
+            top: c.screen_top().ok()?,
+            width: c.width().ok()?,
+            height: c.height().ok()?,
+        })
+    })
+    .flatten()
+}
+
+/// The direct children of a component. Components that can't contain others
+/// have none.
+pub(crate) fn children(comp: &SAPComponent) -> Vec<SAPComponent> {
+    let Some(Ok(collection)) = with_container!(comp, c => GuiContainer_Impl::children(c)) else {
+        return vec![];
+    };
+    elements(&collection)
+}
+
+/// Every component below a window with the given name and type, wherever it
+/// sits in the window's containers.
+pub(crate) fn find_all_by_name(
+    window: &SAPComponent,
+    name: &str,
+    component_type: &str,
+) -> Vec<SAPComponent> {
+    let (name, component_type) = (name.to_string(), component_type.to_string());
+    let collection = match window {
+        SAPComponent::GuiMainWindow(w) => {
+            GuiVContainer_Impl::find_all_by_name(w, name, component_type)
+        }
+        SAPComponent::GuiModalWindow(w) => {
+            GuiVContainer_Impl::find_all_by_name(w, name, component_type)
+        }
+        SAPComponent::GuiFrameWindow(w) => {
+            GuiVContainer_Impl::find_all_by_name(w, name, component_type)
+        }
+        _ => return vec![],
+    };
+    collection.map(|c| elements(&c)).unwrap_or_default()
+}
+
+/// The components in a collection.
+fn elements(collection: &GuiComponentCollection) -> Vec<SAPComponent> {
+    let count = collection.count().unwrap_or_default();
+    (0..count)
+        .filter_map(|i| collection.element_at(i).ok())
+        .collect()
+}
+
+/// Every component below a component, depth first.
+pub(crate) fn descendants(comp: &SAPComponent) -> Vec<SAPComponent> {
+    let mut all = vec![];
+    for child in children(comp) {
+        let below = descendants(&child);
+        all.push(child);
+        all.extend(below);
+    }
+    all
+}
+
+/// The properties that [`property`] can read.
+pub(crate) const PROPERTIES: [&str; 13] = [
+    "Text",
+    "Tooltip",
+    "Changeable",
+    "Left",
+    "Top",
+    "Width",
+    "Height",
+    "IconName",
+    "DefaultTooltip",
+    "Modified",
+    "Required",
+    "MaxLength",
+    "Numerical",
+];
+
+/// Read a property of a component by name (ignoring case and spaces) as a
+/// string.
+pub(crate) fn property(comp: &SAPComponent, property: &str) -> Result<String, String> {
+    let value = match property.replace(' ', "").to_ascii_lowercase().as_str() {
+        "text" => with_vcomponent!(comp, c => c.text()),
+        "tooltip" => with_vcomponent!(comp, c => c.tooltip()),
+        "changeable" => with_vcomponent!(comp, c => c.changeable().map(|v| v.to_string())),
+        "left" => with_vcomponent!(comp, c => c.left().map(|v| v.to_string())),
+        "top" => with_vcomponent!(comp, c => c.top().map(|v| v.to_string())),
+        "width" => with_vcomponent!(comp, c => c.width().map(|v| v.to_string())),
+        "height" => with_vcomponent!(comp, c => c.height().map(|v| v.to_string())),
+        "iconname" => with_vcomponent!(comp, c => c.icon_name()),
+        "defaulttooltip" => with_vcomponent!(comp, c => c.default_tooltip()),
+        "modified" => with_vcomponent!(comp, c => c.modified().map(|v| v.to_string())),
+        "required" => match comp {
+            SAPComponent::GuiTextField(c) => Some(c.required().map(|v| v.to_string())),
+            SAPComponent::GuiCTextField(c) => Some(c.required().map(|v| v.to_string())),
+            SAPComponent::GuiPasswordField(c) => Some(c.required().map(|v| v.to_string())),
+            SAPComponent::GuiComboBox(c) => Some(c.required().map(|v| v.to_string())),
+            _ => None,
+        },
+        "maxlength" => match comp {
+            SAPComponent::GuiTextField(c) => Some(c.max_length().map(|v| v.to_string())),
+            SAPComponent::GuiCTextField(c) => Some(c.max_length().map(|v| v.to_string())),
+            SAPComponent::GuiPasswordField(c) => Some(c.max_length().map(|v| v.to_string())),
+            _ => None,
+        },
+        "numerical" => match comp {
+            SAPComponent::GuiTextField(c) => Some(c.numerical().map(|v| v.to_string())),
+            SAPComponent::GuiCTextField(c) => Some(c.numerical().map(|v| v.to_string())),
+            SAPComponent::GuiPasswordField(c) => Some(c.numerical().map(|v| v.to_string())),
+            _ => None,
+        },
+        _ => {
+            return Err(format!(
+                "Unknown property {property}. Expected one of: {}",
+                PROPERTIES.join(", ")
+            ))
+        }
+    };
+
+    value
+        .ok_or_else(|| {
+            format!(
+                "{} components don't have the property {property}.",
+                type_name(comp).unwrap_or_else(|| String::from("These"))
+            )
+        })?
+        .map_err(|e| format!("Couldn't read {property}: {e}"))
+}
+
+/// Give a visual component the focus, and if it is a text field and a caret
+/// position is given, move the caret there.
+pub(crate) fn set_focus(comp: &SAPComponent, caret_position: Option<i32>) -> Result<(), String> {
+    with_vcomponent!(comp, c => c.set_focus())
+        .ok_or_else(|| {
+            format!(
+                "{} components can't have the focus.",
+                type_name(comp).unwrap_or_else(|| String::from("These"))
+            )
+        })?
+        .map_err(|e| format!("Can't set focus: {e}"))?;
+
+    let Some(position) = caret_position else {
+        return Ok(());
+    };
+    match comp {
+        SAPComponent::GuiTextField(c) => c.set_caret_position(position),
+        SAPComponent::GuiCTextField(c) => c.set_caret_position(position),
+        SAPComponent::GuiPasswordField(c) => c.set_caret_position(position),
+        _ => return Err(String::from("Only text fields have a caret position.")),
+    }
+    .map_err(|e| format!("Can't set caret position: {e}"))
+}
+
+/// The ID of the component that has the focus in a window.
+pub(crate) fn focused(window: &SAPComponent) -> Result<String, String> {
+    let focus = match window {
+        SAPComponent::GuiMainWindow(w) => w.gui_focus(),
+        SAPComponent::GuiModalWindow(w) => w.gui_focus(),
+        SAPComponent::GuiFrameWindow(w) => w.gui_focus(),
+        _ => return Err(String::from("The target isn't a window.")),
+    }
+    .map_err(|_| String::from("Nothing has the focus."))?;
+    id(&focus).ok_or(String::from(
+        "Couldn't get the ID of the focused component.",
+    ))
+}
+
+/// Describe a component and everything below it as JSON.
+pub(crate) fn dump_json(comp: &SAPComponent) -> serde_json::Value {
+    serde_json::json!({
+        "id": id(comp),
+        "type": type_name(comp),
+        "name": name(comp),
+        "text": text(comp),
+        "tooltip": tooltip(comp),
+        "changeable": changeable(comp),
+        "bounds": bounds(comp).map(|b| serde_json::json!({
+            "left": b.left,
+            "top": b.top,
+            "width": b.width,
+            "height": b.height,
+        })),
+        "children": children(comp).iter().map(dump_json).collect::<Vec<_>>(),
+    })
+}
+
+/// Describe a component and everything below it as indented text, one
+/// component per line.
+pub(crate) fn dump_text(comp: &SAPComponent, depth: usize, out: &mut String) {
+    use std::fmt::Write as _;
+
+    let _ = write!(
+        out,
+        "{}{} {} name={:?} text={:?} tooltip={:?}",
+        "  ".repeat(depth),
+        type_name(comp).unwrap_or_default(),
+        id(comp).unwrap_or_default(),
+        name(comp).unwrap_or_default(),
+        text(comp).unwrap_or_default(),
+        tooltip(comp).unwrap_or_default(),
+    );
+    if let Some(changeable) = changeable(comp) {
+        let _ = write!(out, " changeable={changeable}");
+    }
+    if let Some(b) = bounds(comp) {
+        let _ = write!(
+            out,
+            " bounds=({}, {}, {}x{})",
+            b.left, b.top, b.width, b.height
+        );
+    }
+    out.push('\n');
+
+    for child in children(comp) {
+        dump_text(&child, depth + 1, out);
+    }
+}
+
+/// The ID of the topmost window of a session, e.g. `wnd[1]` while a popup is
+/// open.
+pub(crate) fn top_window(session: &GuiSession) -> String {
+    let mut top = String::from("wnd[0]");
+    for n in 1.. {
+        let id = format!("wnd[{n}]");
+        if session.find_by_id(id.clone()).is_err() {
+            break;
+        }
+        top = id;
+    }
+    top
+}
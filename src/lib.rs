@@ -1,15 +1,31 @@
 #![warn(clippy::pedantic)]
 
-use std::fs;
+use std::{
+    collections::HashMap,
+    fs,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
 
+use regex::Regex;
 use sap_scripting::{
-    GuiButton_Impl, GuiCheckBox_Impl, GuiComboBox_Impl, GuiComponentCollection_Impl,
-    GuiComponent_Impl, GuiContainer_Impl, GuiFrameWindow_Impl, GuiGridView_Impl, GuiSession,
-    GuiSession_Impl, GuiStatusbar_Impl, GuiTab_Impl, GuiTableControl_Impl, GuiTableRow_Impl,
-    GuiVComponent_Impl, SAPComInstance, SAPComponent,
+    GuiApplication, GuiApplication_Impl, GuiButton_Impl, GuiCheckBox_Impl, GuiComboBox_Impl,
+    GuiComponentCollection_Impl, GuiComponent_Impl, GuiConnection_Impl, GuiContainer_Impl,
+    GuiFrameWindow_Impl, GuiGridView, GuiGridView_Impl, GuiRadioButton_Impl, GuiSession,
+    GuiSessionInfo, GuiSessionInfo_Impl, GuiSession_Impl, GuiStatusbar_Impl, GuiTab_Impl,
+    GuiTableControl_Impl, GuiTableRow_Impl, GuiVComponent_Impl, SAPComInstance, SAPComponent,
 };
 use testangel_engine::{engine, Evidence, EvidenceContent};
 
+use crate::repository::Repository;
+
+mod component;
+mod repository;
+mod selector;
+mod target;
+mod textedit;
+
 engine! {
     /// Work with SAP
     #[engine(
@@ -19,14 +35,18 @@ engine! {
     #[allow(clippy::upper_case_acronyms)]
     struct SAP {
         com_instance: Option<SAPComInstance>,
-        session: Option<GuiSession>,
+        sessions: HashMap<String, HeldSession>,
+        active_session: Option<String>,
+        config: Config,
+        repository: Arc<Repository>,
     }
 
     impl SAP {
         /// Connect to an SAP instance that the user already has open. If they
         /// have multiple open, this will give access to any of the open windows
-        /// (although most instructions use the main window). This will do
-        /// nothing if we already hold a connection.
+        /// (although most instructions use the main window). The session is held
+        /// as 'main' and made active, and the object repository is loaded if one
+        /// is configured. This will do nothing if we already hold a connection.
         #[instruction(
             id = "sap-connect",
             lua_name = "Connect",
@@ -35,7 +55,304 @@ engine! {
         )]
         fn connect() {
             if state.com_instance.is_none() && !dry_run {
-                connect(state)?;
+                connect(state, &SessionFilter::default())?;
+            }
+        }
+
+        /// Connect to a specific SAP session that the user already has open,
+        /// chosen by matching against the session information. Leave a filter
+        /// empty (or the session number as 0) to match anything. The session is
+        /// held as 'main' and made active, replacing any session held as 'main'
+        /// unless this engine opened it.
+        #[instruction(
+            id = "sap-connect-filtered",
+            lua_name = "ConnectFiltered",
+            name = "Connect to Open Instance (Filtered)",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn connect_filtered(
+            #[arg(name = "System Name")] system_name: String,
+            client: String,
+            user: String,
+            #[arg(name = "Session Number")] session_number: i32,
+        ) {
+            if !dry_run {
+                connect(state, &SessionFilter { system_name, client, user, session_number, ..Default::default() })?;
+            }
+        }
+
+        /// Open a new connection from a SAP Logon entry (by its description) or
+        /// from a connection string (starting with '/', e.g. '/H/host/S/3200'),
+        /// then log on. If the system asks for a password change, the new
+        /// password is used; leave it empty to fail in that case instead. The
+        /// session is held as 'main' and made active, and the object repository
+        /// is loaded if one is configured. Fails if the session held as 'main'
+        /// was also opened by this engine.
+        #[instruction(
+            id = "sap-open-connection",
+            lua_name = "OpenConnection",
+            name = "Open Connection and Log On",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn open_connection(
+            #[arg(name = "SAP Logon Entry or Connection String")] connection: String,
+            client: String,
+            user: String,
+            password: String,
+            #[arg(name = "New Password (if prompted)")] new_password: String,
+            language: String,
+        ) {
+            if !dry_run {
+                open_connection(state, &connection)?;
+                let session = get_session(state, evidence)?;
+                logon(session, LogonDetails { client, user, password, new_password, language })?;
+                settle(state, evidence)?;
+            }
+        }
+
+        /// Open a new session (a new window) on the same connection as the
+        /// active session, and hold it under the given name. The new session
+        /// becomes the active session.
+        #[instruction(
+            id = "sap-session-create",
+            lua_name = "CreateSession",
+            name = "Session: Create",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn session_create(
+            #[arg(name = "Session Name")] name: String,
+        ) {
+            if !dry_run {
+                create_session(state, evidence, name)?;
+                settle(state, evidence)?;
+            }
+        }
+
+        /// Attach to a session that is already open, chosen by matching against
+        /// the session information, and hold it under the given name. Leave a
+        /// filter empty (or the session number as 0) to match anything. The
+        /// session becomes the active session. The name can't already be in
+        /// use.
+        #[instruction(
+            id = "sap-session-attach",
+            lua_name = "AttachSession",
+            name = "Session: Attach",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn session_attach(
+            #[arg(name = "Session Name")] name: String,
+            #[arg(name = "System Name")] system_name: String,
+            client: String,
+            user: String,
+            #[arg(name = "Session Number")] session_number: i32,
+        ) {
+            if !dry_run {
+                check_can_hold(state, &name)?;
+                let (com_instance, session) =
+                    find_open_session(&SessionFilter { system_name, client, user, session_number, ..Default::default() })?;
+                hold_session(state, com_instance, name, session, false);
+            }
+        }
+
+        /// Switch the active session to another session that we hold. All other
+        /// instructions work against the active session.
+        #[instruction(
+            id = "sap-session-switch",
+            lua_name = "SwitchSession",
+            name = "Session: Switch",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn session_switch(
+            #[arg(name = "Session Name")] name: String,
+        ) {
+            if !dry_run {
+                state
+                    .sessions
+                    .get(&name)
+                    .ok_or_else(|| format!("No session is held with the name {name}."))?;
+                state.active_session = Some(name);
+            }
+        }
+
+        /// List the names of the sessions we hold, one per line.
+        #[instruction(
+            id = "sap-session-list",
+            lua_name = "ListSessions",
+            name = "Session: List",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn session_list() -> #[output(id = "sessions", name = "Session Names")] String {
+            let mut names: Vec<_> = state.sessions.keys().cloned().collect();
+            names.sort();
+            names.join("\n")
+        }
+
+        /// Get the name of the active session.
+        #[instruction(
+            id = "sap-session-get-active",
+            lua_name = "GetActiveSession",
+            name = "Session: Get Active",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn session_get_active() -> #[output(id = "name", name = "Session Name")] String {
+            state.active_session.clone().unwrap_or_default()
+        }
+
+        /// Check whether SAP GUI scripting is available, explaining what needs
+        /// fixing if it isn't. The report is also added as evidence.
+        #[instruction(
+            id = "sap-diagnostics",
+            lua_name = "Diagnostics",
+            name = "Diagnose Scripting",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn diagnostics() -> #[output(id = "report", name = "Report")] String {
+            let report = diagnose().join("\n");
+            evidence.push(Evidence { label: String::from("SAP scripting diagnostics"), content: EvidenceContent::Textual(report.clone()) });
+            report
+        }
+
+        /// Get a field of the active session's information. The field can be
+        /// one of 'SystemName', 'Client', 'User', 'Language', 'Transaction',
+        /// 'Program', 'ScreenNumber', 'ApplicationServer', 'GuiCodepage',
+        /// 'ResponseTime', 'RoundTrips' or 'Flushes'. Numbers are returned as
+        /// text.
+        #[instruction(
+            id = "sap-session-get-info",
+            lua_name = "GetSessionInfo",
+            name = "Session: Get Info",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn session_get_info(
+            field: String,
+        ) -> #[output(id = "value", name = "Value")] String {
+            let session = get_session(state, evidence)?;
+            let info = session.info().map_err(|e| format!("Couldn't get session information: {e}"))?;
+            session_info_field(&info, &field)?
+        }
+
+        /// Configure how components are looked up. Every instruction that takes
+        /// a target waits up to the implicit wait for it to appear (0 to not
+        /// wait), checking every poll interval (at least 1 ms). Calls that SAP
+        /// rejects because it is busy are retried up to the retry count. These
+        /// can also be set with the environment variables
+        /// TESTANGEL_SAP_IMPLICIT_WAIT, TESTANGEL_SAP_POLL_INTERVAL and
+        /// TESTANGEL_SAP_RETRIES.
+        #[instruction(
+            id = "sap-configure",
+            lua_name = "Configure",
+            name = "Configure",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn configure(
+            #[arg(name = "Implicit Wait (ms)")] implicit_wait: i32,
+            #[arg(name = "Poll Interval (ms)")] poll_interval: i32,
+            retries: i32,
+        ) {
+            u32::try_from(implicit_wait).map_err(|_| "The implicit wait can't be negative.")?;
+            if poll_interval < 1 {
+                return Err("The poll interval must be at least 1 ms.".into());
+            }
+            u32::try_from(retries).map_err(|_| "The retry count can't be negative.")?;
+            state.config.implicit_wait = implicit_wait;
+            state.config.poll_interval = poll_interval;
+            state.config.retries = retries;
+        }
+
+        /// Choose what happens when the active session goes stale, for example
+        /// because its window was closed or it timed out: 'reattach' to an
+        /// equivalent session (same system, client and user), 'fail' straight
+        /// away, or 'screenshot' to fail with a screenshot of an equivalent
+        /// session if there is one. The default is 'reattach'.
+        #[instruction(
+            id = "sap-set-stale-session-policy",
+            lua_name = "SetStaleSessionPolicy",
+            name = "Session: Set Stale Session Policy",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn set_stale_session_policy(
+            policy: String,
+        ) {
+            state.config.stale_session_policy = policy.parse()?;
+        }
+
+        /// Set the object repository (a TOML or JSON file) that maps logical
+        /// names to targets, which can then be used as 'repo:<name>'. It is
+        /// loaded when connecting, or straight away if already connected. Leave
+        /// the path empty to stop using one. This can also be set with the
+        /// environment variable TESTANGEL_SAP_OBJECT_REPOSITORY.
+        #[instruction(
+            id = "sap-set-object-repository",
+            lua_name = "SetObjectRepository",
+            name = "Set Object Repository",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn set_object_repository(
+            path: String,
+        ) {
+            state.config.object_repository = Some(path).filter(|path| !path.trim().is_empty());
+            if state.com_instance.is_some() && !dry_run {
+                load_repository(state)?;
+            }
+        }
+
+        /// Close the active session and stop holding it. Another session must
+        /// be switched to before continuing.
+        #[instruction(
+            id = "sap-session-close",
+            lua_name = "CloseSession",
+            name = "Session: Close",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn session_close() {
+            if !dry_run {
+                close_active_session(state, evidence)?;
+            }
+        }
+
+        /// Log off the connection of the active session. If 'Confirm' is set,
+        /// this logs off straight away, losing any unsaved data. Otherwise, the
+        /// usual log off confirmation is accepted, but if SAP warns about
+        /// unsaved data the log off is cancelled and this fails. Every session
+        /// held on the connection is released.
+        #[instruction(
+            id = "sap-log-off",
+            lua_name = "LogOff",
+            name = "Log Off",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn log_off(
+            #[arg(name = "Confirm (lose unsaved data)")] confirm: bool,
+        ) {
+            if !dry_run {
+                log_off(state, evidence, confirm)?;
+            }
+        }
+
+        /// Release all held sessions and the connection to SAP without closing
+        /// anything. 'Connect' can be used again afterwards.
+        #[instruction(
+            id = "sap-reset",
+            lua_name = "Reset",
+            name = "Reset",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn reset() {
+            reset(state);
+        }
+
+        /// Close every session that this engine opened itself (with 'Open
+        /// Connection and Log On' or 'Session: Create'), leaving sessions the
+        /// user had open alone, then release everything as with 'Reset'.
+        #[instruction(
+            id = "sap-teardown",
+            lua_name = "Teardown",
+            name = "Teardown",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn teardown() {
+            if !dry_run {
+                teardown(state)?;
             }
         }
 
@@ -49,8 +366,9 @@ engine! {
         fn run_transaction(
             #[arg(name = "Transaction Code")] tcode: String,
         ) {
-            let session = get_session(state)?;
+            let session = get_session(state, evidence)?;
             session.start_transaction(tcode.clone()).map_err(|e| format!("Couldn't execute transaction. {e}"))?;
+            settle(state, evidence)?;
         }
 
         /// Take a screenshot of a SAP window
@@ -64,27 +382,41 @@ engine! {
             #[arg(name = "Evidence Label")] label: String,
             #[arg(name = "Target (usually 'wnd[0]')")] target: String,
         ) {
-            use base64::{Engine as _, engine::general_purpose};
+            let wnd = find(state, evidence, &target)?;
+            screenshot_window(&wnd, label, evidence)?;
+        }
 
-            let session = get_session(state)?;
-            let wnd = session.find_by_id(target.clone()).map_err(|_| format!("Couldn't find {target}."))?;
-            let path = match wnd {
-                SAPComponent::GuiMainWindow(wnd) => wnd
-                    .hard_copy("evidence.png".to_string(), 2)
-                    .map_err(|e| format!("Can't screenshot: {e}")),
-                SAPComponent::GuiFrameWindow(wnd) => wnd
-                    .hard_copy("evidence.png".to_string(), 2)
-                    .map_err(|e| format!("Can't screenshot: {e}")),
-                _ => Err("No valid target to screenshot.".to_string()),
+        /// Record every component under a target (usually 'wnd[0]', which is
+        /// used if left empty) as evidence, with its ID, type, name, text,
+        /// tooltip, whether it is changeable and its position on the screen.
+        /// The format is either 'json' or 'text' (indented by depth).
+        #[instruction(
+            id = "sap-dump-tree",
+            lua_name = "DumpTreeAsEvidence",
+            name = "Dump Component Tree as Evidence",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn dump_tree(
+            #[arg(name = "Evidence Label")] label: String,
+            #[arg(name = "Target (usually 'wnd[0]')")] target: String,
+            format: String,
+        ) {
+            let target = if target.trim().is_empty() { String::from("wnd[0]") } else { target };
+            let comp = find(state, evidence, &target)?;
+            let content = match format.trim().to_ascii_lowercase().as_str() {
+                "json" => serde_json::to_string_pretty(&component::dump_json(&comp))
+                    .map_err(|e| format!("Couldn't write the component tree as JSON: {e}")),
+                "text" => {
+                    let mut out = String::new();
+                    component::dump_text(&comp, 0, &mut out);
+                    Ok(out)
+                }
+                _ => Err(format!("Unknown format {format}. Expected json or text.")),
             }?;
-            // Read path, add to evidence, delete file
-            let data = fs::read(&path).map_err(|e| format!("Failed to read screenshot: {e}"))?;
-
-            let b64_data = general_purpose::STANDARD.encode(data);
-            evidence.push(Evidence { label, content: EvidenceContent::ImageAsPngBase64(b64_data) });
-
-            // try to delete, but don't worry if we can't
-            let _ = fs::remove_file(path);
+            evidence.push(Evidence {
+                label,
+                content: EvidenceContent::Textual(content),
+            });
         }
 
         /// Check if an element exists and returns a boolean.
@@ -97,8 +429,185 @@ engine! {
         fn does_element_exist(
             target: String,
         ) -> #[output(id = "exists", name = "Exists")] bool {
-            let session = get_session(state)?;
-            session.find_by_id(target.clone()).is_ok()
+            let repository = Arc::clone(&state.repository);
+            let session = get_session(state, evidence)?;
+            exists(session, &repository, &target)
+        }
+
+        /// Check whether a component can be changed, i.e. isn't read-only.
+        #[instruction(
+            id = "sap-is-changeable",
+            lua_name = "IsChangeable",
+            name = "Is Changeable",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn is_changeable(
+            target: String,
+        ) -> #[output(id = "changeable", name = "Changeable")] bool {
+            component_state(state, evidence, &target, "changeable")?
+        }
+
+        /// Check whether a field must be filled in.
+        #[instruction(
+            id = "sap-is-required",
+            lua_name = "IsRequired",
+            name = "Is Required",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn is_required(
+            target: String,
+        ) -> #[output(id = "required", name = "Required")] bool {
+            component_state(state, evidence, &target, "required")?
+        }
+
+        /// Check whether a component has been changed since the screen was
+        /// last sent to the server.
+        #[instruction(
+            id = "sap-is-modified",
+            lua_name = "IsModified",
+            name = "Is Modified",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn is_modified(
+            target: String,
+        ) -> #[output(id = "modified", name = "Modified")] bool {
+            component_state(state, evidence, &target, "modified")?
+        }
+
+        /// Check whether a component is shown on the screen. Hidden fields
+        /// aren't part of the screen at all, so like 'Does Element Exist' this
+        /// doesn't wait for the target to appear.
+        #[instruction(
+            id = "sap-is-visible",
+            lua_name = "IsVisible",
+            name = "Is Visible",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn is_visible(
+            target: String,
+        ) -> #[output(id = "visible", name = "Visible")] bool {
+            component_state(state, evidence, &target, "visible")?
+        }
+
+        /// Assert that a component is (or isn't) in a state: 'changeable',
+        /// 'required', 'modified' or 'visible'. If it isn't as expected, a
+        /// screenshot is added to the evidence and the flow fails.
+        #[instruction(
+            id = "sap-assert-state",
+            lua_name = "AssertState",
+            name = "Assert State",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn assert_state(
+            target: String,
+            #[arg(name = "State")] state_name: String,
+            expected: bool,
+        ) {
+            if component_state(state, evidence, &target, &state_name)? != expected {
+                let message = format!(
+                    "Expected {target} {}to be {state_name}.",
+                    if expected { "" } else { "not " },
+                );
+                let session = get_session(state, evidence)?;
+                let window = component::top_window(session);
+                let _ = take_screenshot(session, &window, message.clone(), evidence);
+                return Err(message.into());
+            }
+        }
+
+        /// Resolve a target into the scripting ID of the component it refers
+        /// to. As well as plain IDs, targets can be written as 'label:<text>'
+        /// to refer to the input field that goes with a label on the screen,
+        /// as 'name:<field>' to refer to a field by its name wherever it is on
+        /// the screen, as 'repo:<name>' to refer to a logical name from the
+        /// object repository, or as a selector such as
+        /// 'wnd[0]/usr//GuiCTextField[name=BUKRS]'.
+        #[instruction(
+            id = "sap-resolve-target",
+            lua_name = "ResolveTarget",
+            name = "Resolve Target",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn resolve_target(
+            target: String,
+        ) -> #[output(id = "id", name = "ID")] String {
+            let repository = Arc::clone(&state.repository);
+            let session = get_session(state, evidence)?;
+            let resolved = target::resolve(session, &repository, &target)?;
+            report_healed(evidence, resolved.healed);
+            resolved.id
+        }
+
+        /// Wait for an element to exist, checking every interval until the
+        /// timeout passes. Returns whether the element appeared in time.
+        #[instruction(
+            id = "sap-wait-for-element",
+            lua_name = "WaitForElement",
+            name = "Wait for Element",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn wait_for_element(
+            target: String,
+            #[arg(name = "Timeout (ms)")] timeout: i32,
+            #[arg(name = "Interval (ms)")] interval: i32,
+        ) -> #[output(id = "found", name = "Found")] bool {
+            let repository = Arc::clone(&state.repository);
+            let session = get_session(state, evidence)?;
+            poll(timeout, interval, || exists(session, &repository, &target))?
+        }
+
+        /// Wait for an element to no longer exist, checking every interval until
+        /// the timeout passes. Returns whether the element disappeared in time.
+        #[instruction(
+            id = "sap-wait-for-element-to-disappear",
+            lua_name = "WaitForElementToDisappear",
+            name = "Wait for Element to Disappear",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn wait_for_element_to_disappear(
+            target: String,
+            #[arg(name = "Timeout (ms)")] timeout: i32,
+            #[arg(name = "Interval (ms)")] interval: i32,
+        ) -> #[output(id = "gone", name = "Disappeared")] bool {
+            let repository = Arc::clone(&state.repository);
+            let session = get_session(state, evidence)?;
+            poll(timeout, interval, || !exists(session, &repository, &target))?
+        }
+
+        /// Wait until the session has finished any round trip to the server,
+        /// checking every interval until the timeout passes. Returns whether the
+        /// session became idle in time.
+        #[instruction(
+            id = "sap-wait-until-idle",
+            lua_name = "WaitUntilIdle",
+            name = "Wait Until Idle",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn wait_until_idle(
+            #[arg(name = "Timeout (ms)")] timeout: i32,
+            #[arg(name = "Interval (ms)")] interval: i32,
+        ) -> #[output(id = "idle", name = "Idle")] bool {
+            let session = get_session(state, evidence)?;
+            poll(timeout, interval, || is_idle(session))?
+        }
+
+        /// Choose whether every state-changing instruction (running a
+        /// transaction, sending a key, pressing a button, setting a value and so
+        /// on) waits for the session to become idle before it returns. If the
+        /// session is still busy after the maximum wait, the instruction fails
+        /// with a screenshot.
+        #[instruction(
+            id = "sap-set-wait-for-idle",
+            lua_name = "SetWaitForIdle",
+            name = "Set Wait for Idle",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn set_wait_for_idle(
+            enabled: bool,
+            #[arg(name = "Maximum Wait (ms)")] max_wait: i32,
+        ) {
+            u32::try_from(max_wait).map_err(|_| "The maximum wait can't be negative.")?;
+            state.config.idle_wait = enabled.then_some(max_wait);
         }
 
         /// Return the type string of the component.
@@ -111,8 +620,7 @@ engine! {
         fn component_type(
             target: String,
         ) -> #[output(id = "type", name = "Type")] String {
-            let session = get_session(state)?;
-            let comp = session.find_by_id(target.clone()).map_err(|_| format!("Couldn't find {target}."))?;
+            let comp = find(state, evidence, &target)?;
             match comp {
                 SAPComponent::GuiBarChart(comp) => comp._type().map_err(|e| format!("Failed to get type: {e}")),
                 SAPComponent::GuiBox(comp) => comp._type().map_err(|e| format!("Failed to get type: {e}")),
@@ -172,6 +680,98 @@ engine! {
             }?
         }
 
+        /// Read a property of any component as text. The property is one of
+        /// Text, Tooltip, Changeable, Left, Top, Width, Height, IconName,
+        /// DefaultTooltip, Modified, Required, MaxLength or Numerical, though
+        /// the last three are only available on text fields (and Required on
+        /// combo boxes).
+        #[instruction(
+            id = "sap-get-property",
+            lua_name = "GetProperty",
+            name = "Get Property",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn get_property(
+            target: String,
+            property: String,
+        ) -> #[output(id = "value", name = "Value")] String {
+            let comp = find(state, evidence, &target)?;
+            component::property(&comp, &property)?
+        }
+
+        /// Give a component the focus, as clicking into it would. For text
+        /// fields, the caret can also be moved to a position within the text;
+        /// give -1 to leave it where it is.
+        #[instruction(
+            id = "sap-set-focus",
+            lua_name = "SetFocus",
+            name = "Set Focus",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn set_focus(
+            target: String,
+            #[arg(name = "Caret Position (-1 to leave)")] caret_position: i32,
+        ) {
+            let comp = find(state, evidence, &target)?;
+            component::set_focus(&comp, (caret_position >= 0).then_some(caret_position))?;
+        }
+
+        /// Get the ID of the component that has the focus in the topmost
+        /// window, e.g. to check where the cursor landed after Enter.
+        #[instruction(
+            id = "sap-get-focus",
+            lua_name = "GetFocus",
+            name = "Get Focused Component",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn get_focus() -> #[output(id = "id", name = "ID")] String {
+            let session = get_session(state, evidence)?;
+            let window_id = component::top_window(session);
+            let window = session
+                .find_by_id(window_id.clone())
+                .map_err(|_| format!("Couldn't find {window_id}."))?;
+            component::focused(&window)?
+        }
+
+        /// List the components inside a container, one per line as
+        /// 'ID<tab>Type<tab>Name'. Set recursive to list everything below the
+        /// container rather than only its direct children. Give a type (e.g.
+        /// 'GuiCTextField') to only list components of that type, or leave it
+        /// empty to list them all.
+        #[instruction(
+            id = "sap-list-children",
+            lua_name = "ListChildren",
+            name = "List Children",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn list_children(
+            target: String,
+            recursive: bool,
+            #[arg(name = "Type Filter")] type_filter: String,
+        ) -> #[output(id = "children", name = "Children")] String {
+            let comp = find(state, evidence, &target)?;
+            let children = if recursive {
+                component::descendants(&comp)
+            } else {
+                component::children(&comp)
+            };
+            let type_filter = type_filter.trim();
+            children
+                .iter()
+                .filter_map(|c| {
+                    let component_type = component::type_name(c).unwrap_or_default();
+                    (type_filter.is_empty() || component_type == type_filter).then(|| {
+                        format!(
+                            "{}\t{component_type}\t{}",
+                            component::id(c).unwrap_or_default(),
+                            component::name(c).unwrap_or_default(),
+                        )
+                    })
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+
         /// Highlight an element by drawing a red box around it. Useful just before screenshotting.
         #[instruction(
             id = "sap-visualise-element",
@@ -182,8 +782,7 @@ engine! {
         fn visualise_element(
             target: String,
         ) {
-            let session = get_session(state)?;
-            let comp = session.find_by_id(target.clone()).map_err(|_| format!("Couldn't find {target}."))?;
+            let comp = find(state, evidence, &target)?;
             match comp {
                 SAPComponent::GuiBarChart(comp) => comp.visualize(true).map_err(|e| format!("Failed to visualize: {e}")),
                 SAPComponent::GuiBox(comp) => comp.visualize(true).map_err(|e| format!("Failed to visualize: {e}")),
@@ -244,6 +843,7 @@ engine! {
         }
 
         /// Set the value of a fields 'Text' value. The behaviour of this differs depending on the type of field.
+        /// This works on any component with text, but fails if the component is read-only.
         #[instruction(
             id = "sap-set-text-value",
             lua_name = "SetTextValue",
@@ -254,20 +854,13 @@ engine! {
             target: String,
             value: String,
         ) {
-            let session = get_session(state)?;
-            let wnd = session.find_by_id(target.clone()).map_err(|_| format!("Couldn't find {target}."))?;
-            match wnd {
-                SAPComponent::GuiTextField(txt) => txt
-                    .set_text(value)
-                    .map_err(|e| format!("Can't set text: {e}")),
-                SAPComponent::GuiCTextField(txt) => txt
-                    .set_text(value)
-                    .map_err(|e| format!("Can't set text: {e}")),
-                _ => Err("No valid target to set text.".to_string()),
-            }?;
+            let wnd = find(state, evidence, &target)?;
+            component::set_text(&wnd, value)?;
+            settle(state, evidence)?;
         }
 
         /// Get the value of a fields 'Text' value. The behaviour of this differs depending on the type of field.
+        /// This works on any component with text, including labels, buttons and windows.
         #[instruction(
             id = "sap-get-text-value",
             lua_name = "GetTextValue",
@@ -277,23 +870,122 @@ engine! {
         fn get_text_value(
             target: String,
         ) -> #[output(id = "value", name = "Value")] String {
-            let session = get_session(state)?;
-            let wnd = session.find_by_id(target.clone()).map_err(|_| format!("Couldn't find {target}."))?;
-            match wnd {
-                SAPComponent::GuiTextField(txt) => {
-                    txt.text().map_err(|e| format!("Can't get text: {e}"))
-                }
-                SAPComponent::GuiCTextField(txt) => {
-                    txt.text().map_err(|e| format!("Can't get text: {e}"))
-                }
-                SAPComponent::GuiFrameWindow(txt) => {
-                    txt.text().map_err(|e| format!("Can't get text: {e}"))
-                }
-                SAPComponent::GuiMainWindow(txt) => {
-                    txt.text().map_err(|e| format!("Can't get text: {e}"))
-                }
-                _ => Err("No valid target to get text.".to_string()),
-            }?
+            let wnd = find(state, evidence, &target)?;
+            component::get_text(&wnd)?
+        }
+
+        /// Get the full text of a multi-line text editor. Lines are separated
+        /// by '\n'.
+        #[instruction(
+            id = "sap-textedit-get-text",
+            lua_name = "TexteditGetText",
+            name = "Text Editor: Get Text",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn textedit_get_text(
+            target: String,
+        ) -> #[output(id = "text", name = "Text")] String {
+            let editor = textedit::editor(find(state, evidence, &target)?)?;
+            textedit::get_text(&editor)?
+        }
+
+        /// Replace the full text of a multi-line text editor. Any line endings
+        /// can be used.
+        #[instruction(
+            id = "sap-textedit-set-text",
+            lua_name = "TexteditSetText",
+            name = "Text Editor: Set Text",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn textedit_set_text(
+            target: String,
+            text: String,
+        ) {
+            let editor = textedit::editor(find(state, evidence, &target)?)?;
+            textedit::set_text(&editor, &text)?;
+            settle(state, evidence)?;
+        }
+
+        /// Get a single line of a multi-line text editor, numbered from 0.
+        #[instruction(
+            id = "sap-textedit-get-line",
+            lua_name = "TexteditGetLine",
+            name = "Text Editor: Get Line",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn textedit_get_line(
+            target: String,
+            line: i32,
+        ) -> #[output(id = "text", name = "Text")] String {
+            let editor = textedit::editor(find(state, evidence, &target)?)?;
+            textedit::get_line(&editor, line)?
+        }
+
+        /// Replace a single line of a multi-line text editor, numbered from 0.
+        #[instruction(
+            id = "sap-textedit-set-line",
+            lua_name = "TexteditSetLine",
+            name = "Text Editor: Set Line",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn textedit_set_line(
+            target: String,
+            line: i32,
+            text: String,
+        ) {
+            let editor = textedit::editor(find(state, evidence, &target)?)?;
+            textedit::set_line(&editor, line, &text)?;
+            settle(state, evidence)?;
+        }
+
+        /// Count the lines in a multi-line text editor.
+        #[instruction(
+            id = "sap-textedit-line-count",
+            lua_name = "TexteditLineCount",
+            name = "Text Editor: Count Lines",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn textedit_line_count(
+            target: String,
+        ) -> #[output(id = "count", name = "Line Count")] i32 {
+            let editor = textedit::editor(find(state, evidence, &target)?)?;
+            let count = textedit::lines(&editor)?.len();
+            i32::try_from(count).map_err(|_| format!("There are too many lines to count ({count})."))?
+        }
+
+        /// Add text to the end of a multi-line text editor. Start the text with
+        /// a line break to add it on a new line.
+        #[instruction(
+            id = "sap-textedit-append",
+            lua_name = "TexteditAppend",
+            name = "Text Editor: Append Text",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn textedit_append(
+            target: String,
+            text: String,
+        ) {
+            let editor = textedit::editor(find(state, evidence, &target)?)?;
+            textedit::append(&editor, &text)?;
+            settle(state, evidence)?;
+        }
+
+        /// Select a range of text in a multi-line text editor, from the start
+        /// character up to the end character, where each line break counts as
+        /// one character.
+        #[instruction(
+            id = "sap-textedit-select-range",
+            lua_name = "TexteditSelectRange",
+            name = "Text Editor: Select Range",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn textedit_select_range(
+            target: String,
+            start: i32,
+            end: i32,
+        ) {
+            let editor = textedit::editor(find(state, evidence, &target)?)?;
+            textedit::select(&editor, start, end)?;
         }
 
         /// Send a keypress to the SAP system.
@@ -306,14 +998,14 @@ engine! {
         fn send_key(
             #[arg(name = "Key (VKey)")] key: i32,
         ) {
-            let session = get_session(state)?;
-            let wnd = session.find_by_id("wnd[0]".to_owned()).map_err(|_| String::from("SAP window couldn't be requested."))?;
+            let wnd = find(state, evidence, "wnd[0]")?;
             match wnd {
                 SAPComponent::GuiMainWindow(wnd) => wnd
                     .send_v_key(i16::try_from(key)?)
                     .map_err(|e| format!("Couldn't send VKey: {e}")),
                 _ => Err(String::from("SAP window not open")),
             }?;
+            settle(state, evidence)?;
         }
 
         /// Press a button in the UI.
@@ -326,14 +1018,14 @@ engine! {
         fn press_button(
             target: String,
         ) {
-            let session = get_session(state)?;
-            let comp = session.find_by_id(target).map_err(|_| String::from("Failed to find component"))?;
+            let comp = find(state, evidence, &target)?;
             match comp {
                 SAPComponent::GuiButton(b) => {
                     b.press().map_err(|e| format!("Couldn't press button: {e}"))
                 }
                 _ => Err(String::from("Tried to press a non-button")),
             }?;
+            settle(state, evidence)?;
         }
 
         /// Set the state of a checkbox in the UI.
@@ -347,14 +1039,14 @@ engine! {
             target: String,
             #[arg(id = "state", name = "Checked")] cb_state: bool,
         ) {
-            let session = get_session(state)?;
-            let comp = session.find_by_id(target).map_err(|_| String::from("Failed to find component"))?;
+            let comp = find(state, evidence, &target)?;
             match comp {
                 SAPComponent::GuiCheckBox(c) => c
                     .set_selected(cb_state)
                     .map_err(|e| format!("Couldn't set checkbox: {e}")),
                 _ => Err(String::from("Tried to check a non-checkbox")),
             }?;
+            settle(state, evidence)?;
         }
 
         /// Set the key (selected item) of the combo box.
@@ -368,14 +1060,14 @@ engine! {
             target: String,
             key: String,
         ) {
-            let session = get_session(state)?;
-            let wnd = session.find_by_id(target.clone()).map_err(|_| format!("Couldn't find {target}."))?;
+            let wnd = find(state, evidence, &target)?;
             match wnd {
                 SAPComponent::GuiComboBox(cmb) => cmb
                     .set_key(key)
                     .map_err(|e| format!("Can't set combo box key: {e}")),
                 _ => Err("No valid target to set combo box key.".to_string()),
             }?;
+            settle(state, evidence)?;
         }
 
         /// Get the number of rows in a grid.
@@ -388,8 +1080,7 @@ engine! {
         fn grid_get_row_count(
             #[arg(name = "Target Grid")] target: String,
         ) -> #[output(id = "value", name = "Number of rows")] i32 {
-            let session = get_session(state)?;
-            let comp = session.find_by_id(target).map_err(|_| String::from("Failed to find grid"))?;
+            let comp = find(state, evidence, &target)?;
             match comp {
                 SAPComponent::GuiGridView(g) => {
                     if let Ok(row_count) = g.row_count() {
@@ -416,24 +1107,12 @@ engine! {
             #[arg(name = "Column")] col: String,
             #[arg(name = "Double click")] double: bool,
         ) {
-            let session = get_session(state)?;
-            let comp = session.find_by_id(target).map_err(|_| String::from("Failed to find grid"))?;
+            let comp = find(state, evidence, &target)?;
             match comp {
-                SAPComponent::GuiGridView(g) => {
-                    g.set_current_cell(row, col).map_err(|e| format!("Couldn't select cell in grid: {e}"))?;
-                    if double {
-                        g.double_click_current_cell().map_err(|e| {
-                            format!("The grid couldn't be double clicked: {e}")
-                        })?;
-                    } else {
-                        g.click_current_cell().map_err(|e| {
-                            format!("The grid couldn't be clicked: {e}")
-                        })?;
-                    }
-                    Ok(())
-                }
+                SAPComponent::GuiGridView(g) => click_grid_cell(&g, row, col, double),
                 _ => Err(String::from("The grid was invalid.")),
             }?;
+            settle(state, evidence)?;
         }
 
         /// Get the value of a grid cell.
@@ -448,8 +1127,7 @@ engine! {
             row: i32,
             #[arg(name = "Column")] col: String,
         ) -> #[output(id = "value", name = "Value")] String {
-            let session = get_session(state)?;
-            let comp = session.find_by_id(target).map_err(|_| String::from("Failed to find grid view"))?;
+            let comp = find(state, evidence, &target)?;
             match comp {
                 SAPComponent::GuiGridView(g) => {
                     match g.get_cell_value(row, col) {
@@ -463,6 +1141,54 @@ engine! {
             }?
         }
 
+        /// Open the value help (F4) for a field and pick a value from the hit
+        /// list. Restrictions are filled in the search help dialog first, one
+        /// per line as '<target>=<value>' (e.g. 'label:Company Code=1000');
+        /// leave them empty if the hit list appears straight away. The first row
+        /// whose text in the column matches the value is picked. For grid hit
+        /// lists the column is the column name (e.g. 'BUKRS'); classic lists
+        /// are searched in every column. Returns the field's value afterwards.
+        #[instruction(
+            id = "sap-value-help-select",
+            lua_name = "ValueHelpSelect",
+            name = "Value Help: Select Value",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn value_help_select(
+            #[arg(name = "Target Field")] target: String,
+            restrictions: String,
+            column: String,
+            value: String,
+        ) -> #[output(id = "value", name = "Field Value")] String {
+            let restrictions = parse_restrictions(&restrictions)?;
+            let field = find(state, evidence, &target)?;
+            let field_id = component::id(&field).ok_or("Couldn't get the ID of the field.")?;
+            component::set_focus(&field, None)?;
+            let session = get_session(state, evidence)?;
+            send_v_key(session, &component::top_window(session), 4)?;
+            settle(state, evidence)?;
+
+            if !restrictions.is_empty() {
+                for (restriction, restriction_value) in restrictions {
+                    let comp = find(state, evidence, &restriction)?;
+                    component::set_text(&comp, restriction_value)?;
+                }
+                let session = get_session(state, evidence)?;
+                send_v_key(session, &component::top_window(session), 0)?;
+                settle(state, evidence)?;
+            }
+
+            let session = get_session(state, evidence)?;
+            pick_from_hit_list(session, &column, &value)?;
+            settle(state, evidence)?;
+
+            let session = get_session(state, evidence)?;
+            let field = session
+                .find_by_id(field_id.clone())
+                .map_err(|_| format!("Couldn't find {field_id} after picking a value."))?;
+            component::get_text(&field)?
+        }
+
         /// Get the type of message displayed in the status bar shown at the bottom of the SAP window. This could be 'S' (Success), 'W' (Warning), 'E' (Error), 'A' (Abort), 'I' (Information) or '' (No Status).
         #[instruction(
             id = "sap-get-statusbar-state",
@@ -473,8 +1199,7 @@ engine! {
         fn get_statusbar_state(
             #[arg(name = "Target (usually 'wnd[0]/sbar')")] target: String,
         ) -> #[output(id = "status", name = "Status")] String {
-            let session = get_session(state)?;
-            let comp = session.find_by_id(target).map_err(|_| String::from("Failed to find status bar"))?;
+            let comp = find(state, evidence, &target)?;
             match comp {
                 SAPComponent::GuiStatusbar(s) => {
                     if let Ok(status) = s.message_type() {
@@ -487,6 +1212,70 @@ engine! {
             }?
         }
 
+        /// Wait for the status bar (usually 'wnd[0]/sbar', which is used if left
+        /// empty) to show a message of the given type ('S', 'W', 'E', 'A', 'I',
+        /// or empty for any type) whose text matches a regular expression.
+        /// Returns the full text of the message and the capture groups of the
+        /// match, one per line (empty for a group that didn't take part). For
+        /// example, the pattern 'Document (\d+) was posted' captures the
+        /// document number.
+        #[instruction(
+            id = "sap-wait-for-statusbar-message",
+            lua_name = "WaitForStatusBarMessage",
+            name = "Status Bar: Wait for Message",
+            flags = InstructionFlags::AUTOMATIC,
+        )]
+        fn wait_for_statusbar_message(
+            #[arg(name = "Target (usually 'wnd[0]/sbar')")] target: String,
+            #[arg(name = "Message Type")] message_type: String,
+            #[arg(name = "Pattern (regular expression)")] pattern: String,
+            #[arg(name = "Timeout (ms)")] timeout: i32,
+        ) -> (
+            #[output(id = "text", name = "Message Text")] String,
+            #[output(id = "captures", name = "Captures")] String,
+        ) {
+            let target = if target.trim().is_empty() { String::from("wnd[0]/sbar") } else { target };
+            let re = Regex::new(&pattern).map_err(|e| format!("Invalid pattern: {e}"))?;
+
+            let Config { poll_interval, retries, .. } = state.config;
+            let repository = Arc::clone(&state.repository);
+            let session = get_session(state, evidence)?;
+            let mut last = String::new();
+            let mut value = (String::new(), String::new());
+            let mut healed = None;
+            let found = poll(timeout, poll_interval, || {
+                let Ok(SAPComponent::GuiStatusbar(s)) = target::resolve(session, &repository, &target)
+                    .and_then(|resolved| {
+                        healed = resolved.healed;
+                        find_by_id(session, &resolved.id, retries, poll_interval)
+                    })
+                else {
+                    return false;
+                };
+                let kind = s.message_type().unwrap_or_default();
+                let text = s.text().unwrap_or_default();
+                if message_type.is_empty() || kind == message_type {
+                    if let Some(caps) = re.captures(&text) {
+                        let captures = caps
+                            .iter()
+                            .skip(1)
+                            .map(|m| m.map_or("", |m| m.as_str()))
+                            .collect::<Vec<_>>()
+                            .join("\n");
+                        value = (text, captures);
+                        return true;
+                    }
+                }
+                last = format!("{kind}: {text}");
+                false
+            })?;
+            report_healed(evidence, healed);
+
+            found.then_some(value).ok_or(format!(
+                "No matching status bar message appeared within {timeout} ms. The last message was '{last}'."
+            ))?
+        }
+
         /// Select a tab in a tab panel.
         #[instruction(
             id = "sap-tab-select",
@@ -497,14 +1286,14 @@ engine! {
         fn tab_select(
             #[arg(name = "Target Tab")] target: String,
         ) {
-            let session = get_session(state)?;
-            let comp = session.find_by_id(target).map_err(|_| String::from("Failed to find tab"))?;
+            let comp = find(state, evidence, &target)?;
             match comp {
                 SAPComponent::GuiTab(g) => g.select().map_err(|_| {
                     String::from("The tab couldn't be selected.")
                 }),
                 _ => Err(String::from("The tab was invalid")),
             }?;
+            settle(state, evidence)?;
         }
 
         /// Get the number of rows in a table.
@@ -517,8 +1306,7 @@ engine! {
         fn table_get_row_count(
             target: String,
         ) -> #[output(id = "rows", name = "Rows")] i32 {
-            let session = get_session(state)?;
-            let comp = session.find_by_id(target).map_err(|_| String::from("Failed to find table"))?;
+            let comp = find(state, evidence, &target)?;
             match comp {
                 SAPComponent::GuiTableControl(tab) => tab.row_count().map_err(|e| format!("Cannot read number of rows: {e}")),
                 _ => Err(String::from("The table was invalid")),
@@ -536,13 +1324,13 @@ engine! {
             target: String,
             row: i32,
         ) {
-            let session = get_session(state)?;
-            let comp = session.find_by_id(target).map_err(|_| String::from("Failed to find tab"))?;
+            let comp = find(state, evidence, &target)?;
             let row_comp = match comp {
                 SAPComponent::GuiTableControl(tab) => tab.get_absolute_row(row).map_err(|e| format!("Failed to get table row: {e}")),
                 _ => Err(String::from("The table was invalid")),
             }?;
             row_comp.set_selected(true).map_err(|e| format!("Failed to select row: {e}"))?;
+            settle(state, evidence)?;
         }
 
         /// Get the ID of a cell that can be fed into another function's 'Target' parameter.
@@ -557,8 +1345,7 @@ engine! {
             row: i32,
             column: i32,
         ) -> #[output(id = "id", name = "Target ID")] String {
-            let session = get_session(state)?;
-            let comp = session.find_by_id(target).map_err(|_| String::from("Failed to find tab"))?;
+            let comp = find(state, evidence, &target)?;
             let comp = match comp {
                 SAPComponent::GuiTableControl(tab) => tab.get_cell(row, column).map_err(|e| format!("Failed to get table cell: {e}")),
                 _ => Err(String::from("The table was invalid")),
@@ -630,25 +1417,484 @@ engine! {
 unsafe impl Send for SAP {}
 unsafe impl Sync for SAP {}
 
-fn connect(state: &mut SAP) -> std::result::Result<(), String> {
-    let com_instance = SAPComInstance::new().map_err(|_| "Couldn't get COM instance")?;
+/// Filters used to choose which open session to connect to. Empty strings
+/// (and a session number of 0) match any session.
+#[derive(Default)]
+struct SessionFilter {
+    system_name: String,
+    client: String,
+    user: String,
+    session_number: i32,
+    /// Session IDs that should never match.
+    exclude_ids: Vec<String>,
+}
+
+impl SessionFilter {
+    fn matches(
+        &self,
+        session: &GuiSession,
+        info: &GuiSessionInfo,
+    ) -> std::result::Result<bool, String> {
+        if !self.exclude_ids.is_empty() {
+            let id = session
+                .id()
+                .map_err(|e| format!("Couldn't get session ID: {e}"))?;
+            if self.exclude_ids.contains(&id) {
+                return Ok(false);
+            }
+        }
+
+        let system_name = info
+            .system_name()
+            .map_err(|e| format!("Couldn't read system name: {e}"))?;
+        let client = info
+            .client()
+            .map_err(|e| format!("Couldn't read client: {e}"))?;
+        let user = info
+            .user()
+            .map_err(|e| format!("Couldn't read user: {e}"))?;
+        let session_number = info
+            .session_number()
+            .map_err(|e| format!("Couldn't read session number: {e}"))?;
+
+        Ok(
+            (self.system_name.is_empty() || self.system_name.eq_ignore_ascii_case(&system_name))
+                && (self.client.is_empty() || self.client == client)
+                && (self.user.is_empty() || self.user.eq_ignore_ascii_case(&user))
+                && (self.session_number == 0 || self.session_number == session_number),
+        )
+    }
+}
+
+/// Read a single field of the session information by name. Names are
+/// matched ignoring case and spaces.
+fn session_info_field(info: &GuiSessionInfo, field: &str) -> std::result::Result<String, String> {
+    let value = match field.replace(' ', "").to_ascii_lowercase().as_str() {
+        "systemname" => info.system_name(),
+        "client" => info.client(),
+        "user" => info.user(),
+        "language" => info.language(),
+        "transaction" => info.transaction(),
+        "program" => info.program(),
+        "screennumber" => info.screen_number().map(|v| v.to_string()),
+        "applicationserver" => info.application_server(),
+        "guicodepage" => info.gui_codepage().map(|v| v.to_string()),
+        "responsetime" => info.response_time().map(|v| v.to_string()),
+        "roundtrips" => info.round_trips().map(|v| v.to_string()),
+        "flushes" => info.flushes().map(|v| v.to_string()),
+        _ => return Err(format!("Unknown session information field {field}.")),
+    };
+    value.map_err(|e| format!("Couldn't read {field}: {e}"))
+}
+
+/// Describe a session for error messages, e.g. `DEV/100/USER (session 1)`.
+fn describe_session(info: &GuiSessionInfo) -> String {
+    format!(
+        "{}/{}/{} (session {})",
+        info.system_name().unwrap_or_default(),
+        info.client().unwrap_or_default(),
+        info.user().unwrap_or_default(),
+        info.session_number().unwrap_or_default(),
+    )
+}
+
+/// A session we hold, and whether we opened it ourselves (so that it can be
+/// closed again on teardown). The system, client and user are remembered so
+/// that an equivalent session can be found if this one goes stale.
+struct HeldSession {
+    session: GuiSession,
+    opened_by_engine: bool,
+    system_name: String,
+    client: String,
+    user: String,
+}
+
+impl HeldSession {
+    fn new(session: GuiSession, opened_by_engine: bool) -> Self {
+        let info = session.info().ok();
+        Self {
+            system_name: info
+                .as_ref()
+                .and_then(|info| info.system_name().ok())
+                .unwrap_or_default(),
+            client: info
+                .as_ref()
+                .and_then(|info| info.client().ok())
+                .unwrap_or_default(),
+            user: info
+                .as_ref()
+                .and_then(|info| info.user().ok())
+                .unwrap_or_default(),
+            session,
+            opened_by_engine,
+        }
+    }
+
+    /// A filter matching sessions equivalent to this one.
+    fn equivalent(&self) -> SessionFilter {
+        SessionFilter {
+            system_name: self.system_name.clone(),
+            client: self.client.clone(),
+            user: self.user.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// What to do when the active session has gone stale, for example because
+/// the window was closed or the session timed out.
+#[derive(Default, Clone, Copy)]
+enum StaleSessionPolicy {
+    /// Attach to an equivalent session (same system, client and user).
+    #[default]
+    Reattach,
+    /// Fail straight away.
+    Fail,
+    /// Fail, adding a screenshot of an equivalent session as evidence if one
+    /// is open.
+    FailWithScreenshot,
+}
+
+impl std::str::FromStr for StaleSessionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "reattach" => Ok(Self::Reattach),
+            "fail" => Ok(Self::Fail),
+            "screenshot" => Ok(Self::FailWithScreenshot),
+            _ => Err(format!(
+                "Unknown stale session policy {s}. Expected 'reattach', 'fail' or 'screenshot'."
+            )),
+        }
+    }
+}
+
+/// Engine-wide settings. The defaults can be overridden with environment
+/// variables, and changed at run time with instructions.
+struct Config {
+    /// How long (in ms) to wait for a target to appear.
+    implicit_wait: i32,
+    /// How often (in ms) to check when waiting.
+    poll_interval: i32,
+    /// How many times to retry a call that SAP rejected because it was busy.
+    retries: i32,
+    /// The longest to wait (in ms) for the session to become idle after each
+    /// state-changing instruction, if enabled.
+    idle_wait: Option<i32>,
+    stale_session_policy: StaleSessionPolicy,
+    /// The object repository file to load when connecting, if any.
+    object_repository: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        fn env_var<T: std::str::FromStr>(name: &str, default: T) -> T {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.trim().parse().ok())
+                .unwrap_or(default)
+        }
+
+        Self {
+            implicit_wait: env_var("TESTANGEL_SAP_IMPLICIT_WAIT", 0),
+            poll_interval: env_var("TESTANGEL_SAP_POLL_INTERVAL", 100_i32).max(1),
+            retries: env_var("TESTANGEL_SAP_RETRIES", 3),
+            idle_wait: None,
+            stale_session_policy: StaleSessionPolicy::default(),
+            object_repository: std::env::var("TESTANGEL_SAP_OBJECT_REPOSITORY")
+                .ok()
+                .filter(|path| !path.trim().is_empty()),
+        }
+    }
+}
+
+/// HRESULTs returned when SAP is too busy to handle a call, which are worth
+/// retrying.
+const TRANSIENT_HRESULTS: [u32; 2] = [
+    0x8001_0001, // RPC_E_CALL_REJECTED
+    0x8001_010A, // RPC_E_SERVERCALL_RETRYLATER
+];
+
+/// HRESULTs returned when the COM object behind a session has gone away.
+const DISCONNECTED_HRESULTS: [u32; 4] = [
+    0x8001_0108, // RPC_E_DISCONNECTED
+    0x8001_0012, // RPC_E_SERVER_DIED_DNE
+    0x8007_06BA, // RPC_S_SERVER_UNAVAILABLE
+    0x8007_06BE, // RPC_S_CALL_FAILED
+];
+
+/// Check whether a session's COM object has been disconnected.
+#[allow(clippy::cast_sign_loss)]
+fn is_stale(session: &GuiSession) -> bool {
+    match session.busy() {
+        Err(e) => DISCONNECTED_HRESULTS.contains(&(e.code().0 as u32)),
+        Ok(_) => false,
+    }
+}
+
+/// The name used to hold the session from the connect instructions.
+const MAIN_SESSION: &str = "main";
+
+/// Explanations for why scripting isn't available, with the fix for each.
+const SAP_GUI_NOT_RUNNING: &str =
+    "SAP GUI doesn't appear to be running. Start SAP Logon and open a connection, then try again.";
+const CLIENT_SCRIPTING_DISABLED: &str = "SAP GUI is running, but scripting is disabled on this computer. Enable it in the SAP GUI Options under 'Accessibility & Scripting' > 'Scripting' > 'Enable scripting', then restart SAP Logon.";
+const SERVER_SCRIPTING_DISABLED: &str = "Scripting is disabled by the SAP server. Ask a Basis administrator to set the profile parameter sapgui/user_scripting to TRUE (transaction RZ11), then log on again.";
+const SERVER_SCRIPTING_READ_ONLY: &str = "Scripting is read-only on the SAP server, so nothing can be changed. Ask a Basis administrator to set the profile parameter sapgui/user_scripting_set_readonly to FALSE (transaction RZ11), then log on again.";
+
+fn scripting_engine() -> std::result::Result<(SAPComInstance, GuiApplication), String> {
+    let com_instance =
+        SAPComInstance::new().map_err(|e| format!("Couldn't get COM instance: {e}"))?;
     let wrapper = com_instance
         .sap_wrapper()
-        .map_err(|e| format!("Couldn't get SAP wrapper: {e}"))?;
+        .map_err(|e| format!("{SAP_GUI_NOT_RUNNING} ({e})"))?;
     let engine = wrapper
         .scripting_engine()
-        .map_err(|e| format!("Couldn't get GuiApplication instance: {e}"))?;
+        .map_err(|e| format!("{CLIENT_SCRIPTING_DISABLED} ({e})"))?;
+    Ok((com_instance, engine))
+}
 
-    let SAPComponent::GuiConnection(connection) =
-        sap_scripting::GuiApplication_Impl::children(&engine)
-            .map_err(|e| format!("Couldn't get GuiApplication children: {e}"))?
-            .element_at(0)
-            .map_err(|e| format!("Couldn't get child of GuiApplication: {e}"))?
-    else {
-        return Err(String::from(
-            "Expected GuiConnection, but got something else!",
+/// Work out whether scripting is available, checking SAP GUI, the client
+/// side setting, then each connection and session in turn. Returns one line
+/// per check.
+fn diagnose() -> Vec<String> {
+    let mut report = vec![];
+
+    let Ok(com_instance) = SAPComInstance::new() else {
+        report.push(String::from("Couldn't get COM instance."));
+        return report;
+    };
+    let Ok(wrapper) = com_instance.sap_wrapper() else {
+        report.push(SAP_GUI_NOT_RUNNING.to_string());
+        return report;
+    };
+    report.push(String::from("SAP GUI is running."));
+    let Ok(engine) = wrapper.scripting_engine() else {
+        report.push(CLIENT_SCRIPTING_DISABLED.to_string());
+        return report;
+    };
+    report.push(String::from("Scripting is enabled on this computer."));
+
+    let Ok(connections) = sap_scripting::GuiApplication_Impl::children(&engine) else {
+        report.push(String::from("Couldn't list the open connections."));
+        return report;
+    };
+    let connection_count = connections.count().unwrap_or_default();
+    if connection_count == 0 {
+        report.push(String::from(
+            "There are no open connections. Log on to a system in SAP Logon.",
         ));
+    }
+    for i in 0..connection_count {
+        let Ok(SAPComponent::GuiConnection(connection)) = connections.element_at(i) else {
+            continue;
+        };
+        let description = connection.description().unwrap_or_default();
+        if connection.disabled_by_server().unwrap_or_default() {
+            report.push(format!(
+                "Connection '{description}': {SERVER_SCRIPTING_DISABLED}"
+            ));
+            continue;
+        }
+        report.push(format!(
+            "Connection '{description}': scripting is enabled by the server."
+        ));
+
+        let Ok(sessions) = sap_scripting::GuiConnection_Impl::children(&connection) else {
+            continue;
+        };
+        for j in 0..sessions.count().unwrap_or_default() {
+            let Ok(SAPComponent::GuiSession(session)) = sessions.element_at(j) else {
+                continue;
+            };
+            let Ok(info) = session.info() else {
+                continue;
+            };
+            let description = describe_session(&info);
+            if info.scripting_mode_read_only().unwrap_or_default() {
+                report.push(format!(
+                    "Session {description}: {SERVER_SCRIPTING_READ_ONLY}"
+                ));
+            } else {
+                report.push(format!("Session {description}: scripting is available."));
+            }
+        }
+    }
+
+    report
+}
+
+/// Get the `GuiApplication` from the COM instance we already hold.
+fn application(state: &SAP) -> std::result::Result<GuiApplication, String> {
+    state
+        .com_instance
+        .as_ref()
+        .ok_or("Not connected to SAP".to_string())?
+        .sap_wrapper()
+        .map_err(|e| format!("Couldn't get SAP wrapper: {e}"))?
+        .scripting_engine()
+        .map_err(|e| format!("Couldn't get GuiApplication instance: {e}"))
+}
+
+/// Check that a session can be held under a name. Only 'main' can be
+/// replaced, and only if we didn't open it ourselves, so that teardown still
+/// closes every session we opened.
+fn check_can_hold(state: &SAP, name: &str) -> std::result::Result<(), String> {
+    match state.sessions.get(name) {
+        Some(_) if name != MAIN_SESSION => Err(format!(
+            "A session is already held with the name {name}."
+        )),
+        Some(held) if held.opened_by_engine => Err(format!(
+            "The session held as '{MAIN_SESSION}' was opened by this engine, so it can't be replaced. Log off first."
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Hold a session under a name and make it the active session.
+fn hold_session(
+    state: &mut SAP,
+    com_instance: SAPComInstance,
+    name: String,
+    session: GuiSession,
+    opened_by_engine: bool,
+) {
+    if state.com_instance.is_none() {
+        state.com_instance = Some(com_instance);
+    }
+    state
+        .sessions
+        .insert(name.clone(), HeldSession::new(session, opened_by_engine));
+    state.active_session = Some(name);
+}
+
+fn connect(state: &mut SAP, filter: &SessionFilter) -> std::result::Result<(), String> {
+    check_can_hold(state, MAIN_SESSION)?;
+    // Load the repository first, so that a bad file doesn't leave us
+    // connected without it.
+    load_repository(state)?;
+    let (com_instance, session) = find_open_session(filter)?;
+    hold_session(
+        state,
+        com_instance,
+        MAIN_SESSION.to_string(),
+        session,
+        false,
+    );
+    Ok(())
+}
+
+/// Load the configured object repository, replacing any already loaded.
+fn load_repository(state: &mut SAP) -> std::result::Result<(), String> {
+    state.repository = Arc::new(match &state.config.object_repository {
+        Some(path) => Repository::load(path)?,
+        None => Repository::default(),
+    });
+    Ok(())
+}
+
+/// Find the first open session that matches the filter.
+fn find_open_session(
+    filter: &SessionFilter,
+) -> std::result::Result<(SAPComInstance, GuiSession), String> {
+    let (com_instance, engine) = scripting_engine()?;
+
+    let connections = sap_scripting::GuiApplication_Impl::children(&engine)
+        .map_err(|e| format!("Couldn't get GuiApplication children: {e}"))?;
+    let connection_count = connections
+        .count()
+        .map_err(|e| format!("Couldn't count GuiApplication children: {e}"))?;
+
+    let mut found = vec![];
+    let mut disabled = 0;
+    // A read-only match is only reported if no writable session matches.
+    let mut read_only = None;
+    for i in 0..connection_count {
+        let SAPComponent::GuiConnection(connection) = connections
+            .element_at(i)
+            .map_err(|e| format!("Couldn't get child of GuiApplication: {e}"))?
+        else {
+            return Err(String::from(
+                "Expected GuiConnection, but got something else!",
+            ));
+        };
+        if connection.disabled_by_server().unwrap_or_default() {
+            disabled += 1;
+            continue;
+        }
+
+        let sessions = sap_scripting::GuiConnection_Impl::children(&connection)
+            .map_err(|e| format!("Couldn't get GuiConnection children: {e}"))?;
+        let session_count = sessions
+            .count()
+            .map_err(|e| format!("Couldn't count GuiConnection children: {e}"))?;
+        for j in 0..session_count {
+            let SAPComponent::GuiSession(session) = sessions
+                .element_at(j)
+                .map_err(|e| format!("Couldn't get child of GuiConnection: {e}"))?
+            else {
+                return Err(String::from("Expected GuiSession, but got something else!"));
+            };
+            let info = session
+                .info()
+                .map_err(|e| format!("Couldn't get session information: {e}"))?;
+
+            if filter.matches(&session, &info)? {
+                if !info.scripting_mode_read_only().unwrap_or_default() {
+                    return Ok((com_instance, session));
+                }
+                read_only.get_or_insert_with(|| describe_session(&info));
+                continue;
+            }
+            found.push(describe_session(&info));
+        }
+    }
+
+    if let Some(description) = read_only {
+        Err(format!(
+            "Session {description}: {SERVER_SCRIPTING_READ_ONLY}"
+        ))
+    } else if found.is_empty() && disabled > 0 {
+        Err(SERVER_SCRIPTING_DISABLED.to_string())
+    } else if found.is_empty() {
+        Err(String::from(
+            "There are no open SAP sessions. Log on to a system in SAP Logon, then try again.",
+        ))
+    } else {
+        Err(format!(
+            "No open SAP session matched. Found: {}",
+            found.join(", ")
+        ))
+    }
+}
+
+/// The details entered on the SAP logon screen.
+struct LogonDetails {
+    client: String,
+    user: String,
+    password: String,
+    new_password: String,
+    language: String,
+}
+
+/// Open a new connection from a SAP Logon entry description, or from a
+/// connection string if the target starts with `/` (e.g. `/H/host/S/3200`).
+fn open_connection(state: &mut SAP, target: &str) -> std::result::Result<(), String> {
+    check_can_hold(state, MAIN_SESSION)?;
+    load_repository(state)?;
+    let (com_instance, engine) = scripting_engine()?;
+
+    let connection = if target.starts_with('/') {
+        engine
+            .open_connection_by_connection_string(target.to_string())
+            .map_err(|e| format!("Couldn't open connection to {target}: {e}"))?
+    } else {
+        engine
+            .open_connection(target.to_string())
+            .map_err(|e| format!("Couldn't open connection {target}: {e}"))?
     };
+
     let SAPComponent::GuiSession(session) =
         sap_scripting::GuiConnection_Impl::children(&connection)
             .map_err(|e| format!("Couldn't get GuiConnection children: {e}"))?
@@ -658,15 +1904,710 @@ fn connect(state: &mut SAP) -> std::result::Result<(), String> {
         return Err(String::from("Expected GuiSession, but got something else!"));
     };
 
-    state.com_instance = Some(com_instance);
-    state.session = Some(session);
+    hold_session(state, com_instance, MAIN_SESSION.to_string(), session, true);
 
     Ok(())
 }
 
-fn get_session(state: &SAP) -> std::result::Result<&GuiSession, String> {
-    state
-        .session
+/// List the IDs of the sessions open on a connection.
+fn connection_session_ids(
+    engine: &GuiApplication,
+    connection_id: &str,
+) -> std::result::Result<Vec<String>, String> {
+    let SAPComponent::GuiConnection(connection) = engine
+        .find_by_id(connection_id.to_owned())
+        .map_err(|e| format!("Couldn't find connection {connection_id}: {e}"))?
+    else {
+        return Err(String::from(
+            "Expected GuiConnection, but got something else!",
+        ));
+    };
+
+    let sessions = sap_scripting::GuiConnection_Impl::children(&connection)
+        .map_err(|e| format!("Couldn't get GuiConnection children: {e}"))?;
+    let session_count = sessions
+        .count()
+        .map_err(|e| format!("Couldn't count GuiConnection children: {e}"))?;
+    let mut ids = vec![];
+    for i in 0..session_count {
+        if let SAPComponent::GuiSession(session) = sessions
+            .element_at(i)
+            .map_err(|e| format!("Couldn't get child of GuiConnection: {e}"))?
+        {
+            ids.push(
+                session
+                    .id()
+                    .map_err(|e| format!("Couldn't get session ID: {e}"))?,
+            );
+        }
+    }
+    Ok(ids)
+}
+
+/// Get the ID of the connection a session belongs to. Session IDs look like
+/// `/app/con[0]/ses[0]`.
+fn connection_id(session: &GuiSession) -> std::result::Result<(String, String), String> {
+    let session_id = session
+        .id()
+        .map_err(|e| format!("Couldn't get session ID: {e}"))?;
+    let connection_id = session_id
+        .rsplit_once('/')
+        .map(|(connection_id, _)| connection_id.to_string())
+        .ok_or(format!("Unexpected session ID {session_id}"))?;
+    Ok((connection_id, session_id))
+}
+
+/// Close a session by asking its connection to close it.
+fn close_session(engine: &GuiApplication, session: &GuiSession) -> std::result::Result<(), String> {
+    let (connection_id, session_id) = connection_id(session)?;
+    let SAPComponent::GuiConnection(connection) = engine
+        .find_by_id(connection_id.clone())
+        .map_err(|e| format!("Couldn't find connection {connection_id}: {e}"))?
+    else {
+        return Err(String::from(
+            "Expected GuiConnection, but got something else!",
+        ));
+    };
+    connection
+        .close_session(session_id)
+        .map_err(|e| format!("Couldn't close session: {e}"))
+}
+
+/// Close the active session and stop holding it.
+fn close_active_session(
+    state: &mut SAP,
+    evidence: &mut Vec<Evidence>,
+) -> std::result::Result<(), String> {
+    let engine = application(state)?;
+    close_session(&engine, get_session(state, evidence)?)?;
+    if let Some(name) = state.active_session.take() {
+        state.sessions.remove(&name);
+    }
+    Ok(())
+}
+
+/// Log off the connection of the active session, optionally confirming the
+/// loss of unsaved data, then stop holding every session on that connection.
+fn log_off(
+    state: &mut SAP,
+    evidence: &mut Vec<Evidence>,
+    confirm: bool,
+) -> std::result::Result<(), String> {
+    let (connection_id, _) = connection_id(get_session(state, evidence)?)?;
+    // Once logged off, the sessions can't tell us their connection any more.
+    let names: Vec<_> = state
+        .sessions
+        .iter()
+        .filter(|(_, held)| connection_id_of(&held.session).is_some_and(|id| id == connection_id))
+        .map(|(name, _)| name.clone())
+        .collect();
+
+    let session = get_session(state, evidence)?;
+    // '/nex' logs off straight away, discarding any unsaved data.
+    let command = if confirm { "/nex" } else { "/nend" };
+    session
+        .send_command(command.to_string())
+        .map_err(|e| format!("Couldn't log off: {e}"))?;
+
+    if !confirm {
+        if let Ok(SAPComponent::GuiModalWindow(_)) = session.find_by_id("wnd[1]".to_owned()) {
+            // The usual log off confirmation only asks yes or no, whatever the
+            // logon language. A popup that can also be cancelled is asking
+            // what to do with unsaved data.
+            let is_log_off = session
+                .find_by_id("wnd[1]/usr/btnSPOP-OPTION_CAN".to_owned())
+                .is_err();
+            let option = if is_log_off { "OPTION1" } else { "OPTION_CAN" };
+            match session
+                .find_by_id(format!("wnd[1]/usr/btnSPOP-{option}"))
+                .map_err(|_| String::from("Couldn't find log off confirmation button."))?
+            {
+                SAPComponent::GuiButton(b) => {
+                    b.press().map_err(|e| format!("Couldn't press button: {e}"))
+                }
+                _ => Err(String::from("Tried to press a non-button")),
+            }?;
+            if !is_log_off {
+                return Err(String::from(
+                    "Log off was cancelled as there is unsaved data.",
+                ));
+            }
+        }
+    }
+
+    for name in names {
+        state.sessions.remove(&name);
+    }
+    if state
+        .active_session
         .as_ref()
-        .ok_or("GuiSession not initialised".to_string())
+        .is_some_and(|name| !state.sessions.contains_key(name))
+    {
+        state.active_session = None;
+    }
+    Ok(())
+}
+
+/// Get the connection ID of a session, if it can still be read.
+fn connection_id_of(session: &GuiSession) -> Option<String> {
+    connection_id(session)
+        .ok()
+        .map(|(connection_id, _)| connection_id)
+}
+
+/// Close every session that we opened ourselves, leaving any sessions the
+/// user had open alone, and forget everything we hold.
+fn teardown(state: &mut SAP) -> std::result::Result<(), String> {
+    let mut errors = vec![];
+    if let Ok(engine) = application(state) {
+        for (name, held) in &state.sessions {
+            if held.opened_by_engine {
+                if let Err(e) = close_session(&engine, &held.session) {
+                    errors.push(format!("{name}: {e}"));
+                }
+            }
+        }
+    }
+    reset(state);
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "Some sessions couldn't be closed. {}",
+            errors.join(", ")
+        ))
+    }
+}
+
+/// Forget every session and the COM instance, without closing anything.
+fn reset(state: &mut SAP) {
+    state.sessions.clear();
+    state.active_session = None;
+    state.com_instance = None;
+}
+
+/// Create a new session on the connection of the active session and hold it
+/// under a name.
+fn create_session(
+    state: &mut SAP,
+    evidence: &mut Vec<Evidence>,
+    name: String,
+) -> std::result::Result<(), String> {
+    if state.sessions.contains_key(&name) {
+        return Err(format!("A session is already held with the name {name}."));
+    }
+
+    let engine = application(state)?;
+    let session = get_session(state, evidence)?;
+    let (connection_id, _) = connection_id(session)?;
+
+    let existing = connection_session_ids(&engine, &connection_id)?;
+    session
+        .create_session()
+        .map_err(|e| format!("Couldn't create session: {e}"))?;
+
+    // The new session opens asynchronously, so wait for it to appear.
+    for _ in 0..100 {
+        let new_id = connection_session_ids(&engine, &connection_id)?
+            .into_iter()
+            .find(|id| !existing.contains(id));
+        if let Some(new_id) = new_id {
+            let SAPComponent::GuiSession(new_session) = engine
+                .find_by_id(new_id.clone())
+                .map_err(|e| format!("Couldn't find new session {new_id}: {e}"))?
+            else {
+                return Err(String::from("Expected GuiSession, but got something else!"));
+            };
+            state
+                .sessions
+                .insert(name.clone(), HeldSession::new(new_session, true));
+            state.active_session = Some(name);
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    Err(String::from("The new session didn't open in time."))
+}
+
+/// Fill in the logon screen of a freshly opened session and deal with the
+/// popups that can follow it.
+fn logon(session: &GuiSession, details: LogonDetails) -> std::result::Result<(), String> {
+    set_field_text(session, "wnd[0]/usr/txtRSYST-MANDT", details.client)?;
+    set_field_text(session, "wnd[0]/usr/txtRSYST-BNAME", details.user)?;
+    set_field_text(session, "wnd[0]/usr/pwdRSYST-BCODE", details.password)?;
+    set_field_text(session, "wnd[0]/usr/txtRSYST-LANGU", details.language)?;
+    send_v_key(session, "wnd[0]", 0)?;
+
+    // Invalid credentials leave us on the logon screen with an error.
+    let program = session
+        .info()
+        .and_then(|info| info.program())
+        .map_err(|e| format!("Couldn't read current program: {e}"))?;
+    if program == "SAPMSYST" && session.find_by_id("wnd[1]".to_owned()).is_err() {
+        if let Ok(SAPComponent::GuiStatusbar(sbar)) = session.find_by_id("wnd[0]/sbar".to_owned()) {
+            if sbar.message_type().unwrap_or_default() == "E" {
+                return Err(format!("Logon failed: {}", sbar.text().unwrap_or_default()));
+            }
+        }
+    }
+
+    // Multiple logon: continue with this logon without ending the others.
+    if let Ok(SAPComponent::GuiRadioButton(opt)) =
+        session.find_by_id("wnd[1]/usr/radMULTI_LOGON_OPT2".to_owned())
+    {
+        opt.select()
+            .map_err(|e| format!("Couldn't choose multiple logon option: {e}"))?;
+        send_v_key(session, "wnd[1]", 0)?;
+    }
+
+    // Password change requested by the system.
+    if session
+        .find_by_id("wnd[1]/usr/pwdRSYST-NCODE".to_owned())
+        .is_ok()
+    {
+        if details.new_password.is_empty() {
+            return Err(String::from(
+                "The system requested a password change, but no new password was provided.",
+            ));
+        }
+        set_field_text(
+            session,
+            "wnd[1]/usr/pwdRSYST-NCODE",
+            details.new_password.clone(),
+        )?;
+        set_field_text(session, "wnd[1]/usr/pwdRSYST-NCOD2", details.new_password)?;
+        send_v_key(session, "wnd[1]", 0)?;
+
+        if let Ok(SAPComponent::GuiStatusbar(sbar)) = session.find_by_id("wnd[1]/sbar".to_owned()) {
+            if sbar.message_type().unwrap_or_default() == "E" {
+                return Err(format!(
+                    "Password change failed: {}",
+                    sbar.text().unwrap_or_default()
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Set the text of an input field by ID.
+fn set_field_text(
+    session: &GuiSession,
+    id: &str,
+    value: String,
+) -> std::result::Result<(), String> {
+    match session
+        .find_by_id(id.to_owned())
+        .map_err(|_| format!("Couldn't find {id}."))?
+    {
+        SAPComponent::GuiTextField(txt) => txt.set_text(value),
+        SAPComponent::GuiCTextField(txt) => txt.set_text(value),
+        SAPComponent::GuiPasswordField(txt) => txt.set_text(value),
+        _ => return Err(format!("{id} is not a text field.")),
+    }
+    .map_err(|e| format!("Can't set text of {id}: {e}"))
+}
+
+/// Send a virtual key to a main or modal window.
+fn send_v_key(session: &GuiSession, window: &str, key: i16) -> std::result::Result<(), String> {
+    match session
+        .find_by_id(window.to_owned())
+        .map_err(|_| format!("Couldn't find {window}."))?
+    {
+        SAPComponent::GuiMainWindow(wnd) => wnd.send_v_key(key),
+        SAPComponent::GuiModalWindow(wnd) => wnd.send_v_key(key),
+        _ => return Err(format!("{window} is not a window.")),
+    }
+    .map_err(|e| format!("Couldn't send VKey: {e}"))
+}
+
+/// Split value help restrictions, one per line as `<target>=<value>`, at the
+/// first `=` outside of any selector predicates.
+fn parse_restrictions(text: &str) -> std::result::Result<Vec<(String, String)>, String> {
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let mut depth = 0;
+            let split = line
+                .char_indices()
+                .find(|(_, c)| {
+                    match c {
+                        '[' => depth += 1,
+                        ']' => depth -= 1,
+                        _ => (),
+                    }
+                    *c == '=' && depth == 0
+                })
+                .map(|(i, _)| i)
+                .ok_or(format!(
+                    "The restriction '{line}' should be written as '<target>=<value>'."
+                ))?;
+            Ok((
+                line[..split].trim().to_string(),
+                line[split + 1..].trim().to_string(),
+            ))
+        })
+        .collect()
+}
+
+/// Select a cell of a grid, then click or double click it.
+fn click_grid_cell(
+    grid: &GuiGridView,
+    row: i32,
+    column: String,
+    double: bool,
+) -> std::result::Result<(), String> {
+    grid.set_current_cell(row, column)
+        .map_err(|e| format!("Couldn't select cell in grid: {e}"))?;
+    if double {
+        grid.double_click_current_cell()
+            .map_err(|e| format!("The grid couldn't be double clicked: {e}"))
+    } else {
+        grid.click_current_cell()
+            .map_err(|e| format!("The grid couldn't be clicked: {e}"))
+    }
+}
+
+/// Pick the first row of the value help hit list in the topmost window whose
+/// text matches a value, by double clicking it in a grid (searching the named
+/// column) or choosing it in a classic list (searching every column).
+fn pick_from_hit_list(
+    session: &GuiSession,
+    column: &str,
+    value: &str,
+) -> std::result::Result<(), String> {
+    let window_id = component::top_window(session);
+    let window = session
+        .find_by_id(window_id.clone())
+        .map_err(|_| format!("Couldn't find {window_id}."))?;
+    let descendants = component::descendants(&window);
+
+    if let Some(SAPComponent::GuiGridView(g)) = descendants
+        .iter()
+        .find(|c| matches!(c, SAPComponent::GuiGridView(_)))
+    {
+        let rows = g
+            .row_count()
+            .map_err(|e| format!("The hit list had no row count: {e}"))?;
+        for row in 0..rows {
+            let text = g.get_cell_value(row, column.to_string()).or_else(|_| {
+                // Only the visible rows of a long hit list are loaded, so
+                // scroll this one into view and read it again.
+                g.set_first_visible_row(row)
+                    .map_err(|e| format!("Couldn't scroll the hit list to row {row}: {e}"))?;
+                g.get_cell_value(row, column.to_string()).map_err(|e| {
+                    format!("Couldn't read row {row} of column {column} in the hit list: {e}")
+                })
+            })?;
+            if text.trim() == value.trim() {
+                return click_grid_cell(g, row, column.to_string(), true);
+            }
+        }
+        return Err(format!(
+            "No row in the hit list has '{value}' in column {column}."
+        ));
+    }
+
+    let entry = descendants
+        .iter()
+        .find(|c| {
+            matches!(c, SAPComponent::GuiLabel(_))
+                && component::text(c).is_some_and(|text| text.trim() == value.trim())
+        })
+        .ok_or(format!(
+            "There is no hit list in {window_id} showing '{value}'."
+        ))?;
+    component::set_focus(entry, None)?;
+    send_v_key(session, &window_id, 2)
+}
+
+/// Check a condition every interval (in milliseconds) until it holds or the
+/// timeout (in milliseconds) passes. Returns whether the condition held.
+fn poll(
+    timeout: i32,
+    interval: i32,
+    mut condition: impl FnMut() -> bool,
+) -> std::result::Result<bool, String> {
+    let timeout = u64::try_from(timeout).map_err(|_| "The timeout can't be negative.")?;
+    // Without a pause between checks, this would flood SAP GUI with calls.
+    let interval = u64::try_from(interval)
+        .ok()
+        .filter(|interval| *interval > 0)
+        .ok_or("The interval must be at least 1 ms.")?;
+    let deadline = Instant::now() + Duration::from_millis(timeout);
+
+    loop {
+        if condition() {
+            return Ok(true);
+        }
+        if Instant::now() >= deadline {
+            return Ok(false);
+        }
+        thread::sleep(Duration::from_millis(interval));
+    }
+}
+
+/// Check whether a session has finished any round trip to the server. A
+/// session that can't answer is treated as busy.
+fn is_idle(session: &GuiSession) -> bool {
+    session.busy().is_ok_and(|busy| !busy)
+}
+
+/// If waiting for idle is enabled, wait for the active session to become
+/// idle, failing with a screenshot if it takes longer than allowed.
+fn settle(state: &mut SAP, evidence: &mut Vec<Evidence>) -> std::result::Result<(), String> {
+    let Some(max_wait) = state.config.idle_wait else {
+        return Ok(());
+    };
+    let poll_interval = state.config.poll_interval;
+    let session = get_session(state, evidence)?;
+    if poll(max_wait, poll_interval, || is_idle(session))? {
+        return Ok(());
+    }
+
+    let _ = take_screenshot(
+        session,
+        "wnd[0]",
+        String::from("Session still busy"),
+        evidence,
+    );
+    Err(format!(
+        "The session was still busy after waiting {max_wait} ms."
+    ))
+}
+
+/// Find the component a target refers to in the active session, retrying
+/// calls that SAP rejects while busy and waiting for the implicit wait if it
+/// isn't there.
+fn find(
+    state: &mut SAP,
+    evidence: &mut Vec<Evidence>,
+    target: &str,
+) -> std::result::Result<SAPComponent, String> {
+    let Config {
+        implicit_wait,
+        poll_interval,
+        retries,
+        ..
+    } = state.config;
+    let repository = Arc::clone(&state.repository);
+    let session = get_session(state, evidence)?;
+
+    let locate = || {
+        target::resolve(session, &repository, target).and_then(|resolved| {
+            find_by_id(session, &resolved.id, retries, poll_interval)
+                .map(|comp| (comp, resolved.healed))
+        })
+    };
+    let mut result = locate();
+    if result.is_err() && implicit_wait > 0 {
+        poll(implicit_wait, poll_interval, || {
+            result = locate();
+            result.is_ok()
+        })?;
+    }
+    let (comp, healed) = result?;
+    report_healed(evidence, healed);
+    Ok(comp)
+}
+
+/// Add a note to the evidence when a target was only found by a fallback.
+fn report_healed(evidence: &mut Vec<Evidence>, healed: Option<String>) {
+    if let Some(note) = healed {
+        evidence.push(Evidence {
+            label: String::from("Target found by fallback"),
+            content: EvidenceContent::Textual(note),
+        });
+    }
+}
+
+/// Check whether a target currently exists, without waiting or retrying.
+fn exists(session: &GuiSession, repository: &Repository, target: &str) -> bool {
+    target::resolve(session, repository, target)
+        .is_ok_and(|resolved| session.find_by_id(resolved.id).is_ok())
+}
+
+/// Read whether a target is 'changeable', 'required', 'modified' or
+/// 'visible'.
+fn component_state(
+    state: &mut SAP,
+    evidence: &mut Vec<Evidence>,
+    target: &str,
+    name: &str,
+) -> std::result::Result<bool, String> {
+    match name.trim().to_ascii_lowercase().as_str() {
+        "visible" => {
+            let repository = Arc::clone(&state.repository);
+            let session = get_session(state, evidence)?;
+            Ok(target::resolve(session, &repository, target)
+                .ok()
+                .and_then(|resolved| session.find_by_id(resolved.id).ok())
+                .and_then(|comp| component::bounds(&comp))
+                .is_some_and(|b| b.width > 0 && b.height > 0))
+        }
+        property @ ("changeable" | "required" | "modified") => {
+            let comp = find(state, evidence, target)?;
+            component::property(&comp, property)?
+                .parse()
+                .map_err(|_| format!("Couldn't read whether {target} is {property}."))
+        }
+        _ => Err(format!(
+            "Unknown state {name}. Expected changeable, required, modified or visible."
+        )),
+    }
+}
+
+/// Find a component by ID, retrying up to `retries` times (every
+/// `poll_interval` ms) if SAP rejects the call because it is busy.
+#[allow(clippy::cast_sign_loss)]
+fn find_by_id(
+    session: &GuiSession,
+    target: &str,
+    retries: i32,
+    poll_interval: i32,
+) -> std::result::Result<SAPComponent, String> {
+    let mut attempt = 0;
+    loop {
+        match session.find_by_id(target.to_owned()) {
+            Ok(comp) => return Ok(comp),
+            Err(e) if attempt < retries && TRANSIENT_HRESULTS.contains(&(e.code().0 as u32)) => {
+                attempt += 1;
+                thread::sleep(Duration::from_millis(
+                    u64::try_from(poll_interval).unwrap_or_default(),
+                ));
+            }
+            Err(_) => return Err(format!("Couldn't find {target}.")),
+        }
+    }
+}
+
+/// Take a screenshot of a window and add it to the evidence.
+fn take_screenshot(
+    session: &GuiSession,
+    target: &str,
+    label: String,
+    evidence: &mut Vec<Evidence>,
+) -> std::result::Result<(), String> {
+    let wnd = session
+        .find_by_id(target.to_owned())
+        .map_err(|_| format!("Couldn't find {target}."))?;
+    screenshot_window(&wnd, label, evidence)
+}
+
+/// Take a screenshot of a window component and add it to the evidence.
+fn screenshot_window(
+    wnd: &SAPComponent,
+    label: String,
+    evidence: &mut Vec<Evidence>,
+) -> std::result::Result<(), String> {
+    use base64::{engine::general_purpose, Engine as _};
+
+    let path = match wnd {
+        SAPComponent::GuiMainWindow(wnd) => wnd
+            .hard_copy("evidence.png".to_string(), 2)
+            .map_err(|e| format!("Can't screenshot: {e}")),
+        SAPComponent::GuiFrameWindow(wnd) => wnd
+            .hard_copy("evidence.png".to_string(), 2)
+            .map_err(|e| format!("Can't screenshot: {e}")),
+        _ => Err("No valid target to screenshot.".to_string()),
+    }?;
+    // Read path, add to evidence, delete file
+    let data = fs::read(&path).map_err(|e| format!("Failed to read screenshot: {e}"))?;
+
+    let b64_data = general_purpose::STANDARD.encode(data);
+    evidence.push(Evidence {
+        label,
+        content: EvidenceContent::ImageAsPngBase64(b64_data),
+    });
+
+    // try to delete, but don't worry if we can't
+    let _ = fs::remove_file(path);
+    Ok(())
+}
+
+/// Deal with the active session having gone stale, according to the policy.
+fn recover_stale_session(
+    state: &mut SAP,
+    evidence: &mut Vec<Evidence>,
+    name: &str,
+) -> std::result::Result<(), String> {
+    let held = &state.sessions[name];
+    let description = format!("{}/{}/{}", held.system_name, held.client, held.user);
+    let mut filter = held.equivalent();
+    // Never pick up a session that we already hold under another name.
+    filter.exclude_ids = state
+        .sessions
+        .values()
+        .filter_map(|other| other.session.id().ok())
+        .collect();
+    let stale = format!(
+        "The session '{name}' ({description}) is no longer available. The window may have been closed or the session may have timed out."
+    );
+
+    match state.config.stale_session_policy {
+        StaleSessionPolicy::Fail => Err(stale),
+        StaleSessionPolicy::FailWithScreenshot => {
+            evidence.push(Evidence {
+                label: format!("Stale session '{name}'"),
+                content: EvidenceContent::Textual(stale.clone()),
+            });
+            // The stale session's screen can't be captured any more, so show
+            // what another session on the same system is doing instead.
+            if let Ok((_, session)) = find_open_session(&filter) {
+                let other = session
+                    .id()
+                    .unwrap_or_else(|_| String::from("another session"));
+                let _ = take_screenshot(
+                    &session,
+                    "wnd[0]",
+                    format!("Another session on {description} ({other}), not the stale session '{name}'"),
+                    evidence,
+                );
+            }
+            Err(stale)
+        }
+        StaleSessionPolicy::Reattach => {
+            let (com_instance, session) = find_open_session(&filter)
+                .map_err(|e| format!("{stale} It couldn't be reattached: {e}"))?;
+            let reattached = session.info().map_or_else(
+                |_| session.id().unwrap_or_default(),
+                |info| describe_session(&info),
+            );
+            evidence.push(Evidence {
+                label: format!("Reattached session '{name}'"),
+                content: EvidenceContent::Textual(format!(
+                    "{stale} It has been reattached to {reattached}, so the flow continues in a different window."
+                )),
+            });
+            // The replacement is a window that was already open, quite
+            // possibly the user's own, so teardown must leave it alone.
+            state.com_instance = Some(com_instance);
+            state
+                .sessions
+                .insert(name.to_string(), HeldSession::new(session, false));
+            Ok(())
+        }
+    }
+}
+
+fn get_session<'a>(
+    state: &'a mut SAP,
+    evidence: &mut Vec<Evidence>,
+) -> std::result::Result<&'a GuiSession, String> {
+    let name = state
+        .active_session
+        .clone()
+        .ok_or("GuiSession not initialised".to_string())?;
+    let held = state
+        .sessions
+        .get(&name)
+        .ok_or("GuiSession not initialised".to_string())?;
+
+    if is_stale(&held.session) {
+        recover_stale_session(state, evidence, &name)?;
+    }
+    Ok(&state.sessions[&name].session)
 }
@@ -0,0 +1,223 @@
+//! An object repository maps logical names, such as `PO.Header.CompanyCode`,
+//! to the targets that locate them, so flows don't have to repeat long
+//! scripting IDs. Targets refer to these names as `repo:<name>`.
+//!
+//! A repository is a TOML or JSON file (chosen by its extension) of nested
+//! tables. Each entry is a target, or a list of targets to try in order:
+//!
+//! ```toml
+//! [PO.Header]
+//! CompanyCode = ["name:EKKO-BUKRS", "label:Company Code"]
+//!
+//! # Only used in transaction ME21N, and then only on screen 0014.
+//! [ME21N.0014.PO.Header]
+//! Vendor = "wnd[0]/usr/subSUB0:SAPLMEGUI:0013/ctxtMEPO_TOPLINE-SUPERFIELD"
+//! ```
+//!
+//! Names are looked up first under the current transaction and screen, then
+//! under the current transaction, then on their own.
+//!
+//! An entry can also be a table with an `id` (a target or list of targets)
+//! and other attributes of the component, which are used to find it elsewhere
+//! on the screen if none of its targets match, e.g. after a support package
+//! moves it into a different container:
+//!
+//! ```toml
+//! [PO.Header.CompanyCode]
+//! id = "wnd[0]/usr/subSUB0:SAPLMEGUI:0013/ctxtMEPO1222-BUKRS"
+//! type = "GuiCTextField"
+//! name = "MEPO1222-BUKRS"
+//! tooltip = "Company Code"
+//! label = "Company Code"
+//! # Position relative to the window, in pixels.
+//! left = 312
+//! top = 160
+//! ```
+
+use std::{collections::HashMap, fs};
+
+use serde_json::Value;
+
+/// The attributes of a locator table, other than `id`.
+const ATTRIBUTES: [&str; 6] = ["type", "name", "tooltip", "label", "left", "top"];
+
+/// The targets for each logical name in an object repository.
+#[derive(Default)]
+pub(crate) struct Repository {
+    /// The file the repository was loaded from.
+    path: Option<String>,
+    entries: HashMap<String, Entry>,
+}
+
+/// How to locate the component a logical name refers to.
+#[derive(Default)]
+pub(crate) struct Entry {
+    /// The targets to try in order.
+    pub(crate) targets: Vec<String>,
+    /// The attributes to fall back to if none of the targets match.
+    pub(crate) fallback: Fallback,
+}
+
+/// Attributes of a component that can be used to find it if its targets no
+/// longer match.
+#[derive(Default)]
+pub(crate) struct Fallback {
+    pub(crate) component_type: Option<String>,
+    pub(crate) name: Option<String>,
+    pub(crate) tooltip: Option<String>,
+    pub(crate) label: Option<String>,
+    /// The position relative to the window, in pixels.
+    pub(crate) position: Option<(i32, i32)>,
+}
+
+impl Repository {
+    /// Load a repository from a TOML or JSON file.
+    pub(crate) fn load(path: &str) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Couldn't read object repository {path}: {e}"))?;
+        let root = if path.to_ascii_lowercase().ends_with(".json") {
+            serde_json::from_str(&contents)
+                .map_err(|e| format!("Object repository {path} isn't valid JSON: {e}"))?
+        } else {
+            let table: toml::Table = contents
+                .parse()
+                .map_err(|e| format!("Object repository {path} isn't valid TOML: {e}"))?;
+            serde_json::to_value(table)
+                .map_err(|e| format!("Couldn't read object repository {path}: {e}"))?
+        };
+
+        let mut entries = HashMap::new();
+        flatten("", &root, &mut entries)
+            .map_err(|e| format!("Object repository {path} is invalid: {e}"))?;
+        Ok(Self {
+            path: Some(path.to_string()),
+            entries,
+        })
+    }
+
+    /// The entry for a logical name, preferring entries grouped under the
+    /// current transaction and screen.
+    pub(crate) fn entry(
+        &self,
+        name: &str,
+        transaction: &str,
+        screen: i32,
+    ) -> Result<&Entry, String> {
+        let Some(path) = &self.path else {
+            return Err(format!(
+                "Can't look up '{name}' as no object repository is loaded."
+            ));
+        };
+        [
+            format!("{transaction}.{screen:04}.{name}"),
+            format!("{transaction}.{name}"),
+            name.to_string(),
+        ]
+        .iter()
+        .find_map(|key| self.entries.get(key))
+        .ok_or(format!("'{name}' isn't in the object repository {path}."))
+    }
+}
+
+/// Collect the entries of a table into dotted names.
+fn flatten(
+    prefix: &str,
+    value: &Value,
+    entries: &mut HashMap<String, Entry>,
+) -> Result<(), String> {
+    let entry = match value {
+        Value::Object(table) if table.contains_key("id") => locator(prefix, table)?,
+        Value::Object(table) => {
+            for (key, value) in table {
+                let name = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{prefix}.{key}")
+                };
+                flatten(&name, value, entries)?;
+            }
+            return Ok(());
+        }
+        targets => Entry {
+            targets: targets_of(prefix, targets)?,
+            fallback: Fallback::default(),
+        },
+    };
+    entries.insert(prefix.to_string(), entry);
+    Ok(())
+}
+
+/// Read a locator table: an `id` and fallback attributes.
+fn locator(prefix: &str, table: &serde_json::Map<String, Value>) -> Result<Entry, String> {
+    if let Some(key) = table
+        .keys()
+        .find(|key| *key != "id" && !ATTRIBUTES.contains(&key.as_str()))
+    {
+        return Err(format!(
+            "'{prefix}' has unknown attribute '{key}'. Expected id or one of: {}",
+            ATTRIBUTES.join(", ")
+        ));
+    }
+
+    let text = |key: &str| -> Result<Option<String>, String> {
+        table
+            .get(key)
+            .map(|v| {
+                v.as_str()
+                    .map(str::to_string)
+                    .ok_or(format!("'{prefix}.{key}' must be text."))
+            })
+            .transpose()
+    };
+    let number = |key: &str| -> Result<Option<i32>, String> {
+        table
+            .get(key)
+            .map(|v| {
+                v.as_i64()
+                    .and_then(|n| i32::try_from(n).ok())
+                    .ok_or(format!("'{prefix}.{key}' must be a whole number."))
+            })
+            .transpose()
+    };
+
+    let position = match (number("left")?, number("top")?) {
+        (Some(left), Some(top)) => Some((left, top)),
+        (None, None) => None,
+        _ => {
+            return Err(format!(
+                "'{prefix}' must give both left and top, or neither."
+            ))
+        }
+    };
+    Ok(Entry {
+        targets: targets_of(prefix, &table["id"])?,
+        fallback: Fallback {
+            component_type: text("type")?,
+            name: text("name")?,
+            tooltip: text("tooltip")?,
+            label: text("label")?,
+            position,
+        },
+    })
+}
+
+/// Read a target or list of targets.
+fn targets_of(prefix: &str, value: &Value) -> Result<Vec<String>, String> {
+    let targets = match value {
+        Value::String(target) => vec![target.clone()],
+        Value::Array(targets) => targets
+            .iter()
+            .map(|t| t.as_str().map(str::to_string))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(format!("'{prefix}' must be a list of targets."))?,
+        _ => return Err(format!("'{prefix}' must be a target or list of targets.")),
+    };
+
+    if targets.is_empty() {
+        return Err(format!("'{prefix}' has no targets."));
+    }
+    if targets.iter().any(|t| t.starts_with("repo:")) {
+        return Err(format!("'{prefix}' refers to another repository name."));
+    }
+    Ok(targets)
+}
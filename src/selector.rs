@@ -0,0 +1,546 @@
+//! A small selector language for locating components by walking the
+//! component tree, rather than by their exact scripting ID.
+//!
+//! A selector is made of steps separated by `/` (a direct child) or `//` (any
+//! descendant). Each step is either:
+//!
+//! - part of a scripting ID, e.g. `wnd[0]` or `usr`, or
+//! - a component test: a type such as `GuiButton` (optionally written as
+//!   `type=GuiButton`), or `*` for any type, followed by any number of
+//!   predicates such as `[name=BUKRS]` or `[tooltip~="Save"]`.
+//!
+//! Predicates compare the `id`, `type`, `name`, `text` or `tooltip` of a
+//! component using `=` (equals), `!=` (doesn't equal), `~=` (contains), `^=`
+//! (starts with) or `$=` (ends with). Values can be quoted with `"` or `'`.
+//!
+//! Leading ID steps are looked up directly, e.g. `wnd[0]/usr//GuiCTextField`
+//! searches below `wnd[0]/usr`. A selector that doesn't start with an ID
+//! searches everything below the window it is evaluated against.
+
+/// A component in a tree that selectors can be evaluated against.
+pub(crate) trait Node: Sized {
+    /// The scripting ID of this component.
+    fn id(&self) -> String;
+    /// The value of one of the attributes `id`, `type`, `name`, `text` or
+    /// `tooltip`, if it can be read.
+    fn attribute(&self, attribute: &str) -> Option<String>;
+    /// The direct children of this component.
+    fn children(&self) -> Vec<Self>;
+}
+
+/// The attributes that predicates can compare.
+const ATTRIBUTES: [&str; 5] = ["id", "type", "name", "text", "tooltip"];
+
+/// How a step relates to the step before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Axis {
+    Child,
+    Descendant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    Equals,
+    NotEquals,
+    Contains,
+    StartsWith,
+    EndsWith,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Predicate {
+    attribute: String,
+    operator: Operator,
+    value: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Step {
+    /// One segment of a scripting ID, e.g. `usr`.
+    Id(String),
+    /// A component test, optionally restricted to a type.
+    Test {
+        component_type: Option<String>,
+        predicates: Vec<Predicate>,
+    },
+}
+
+/// A parsed selector.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Selector {
+    /// The scripting ID to start from, made of the leading ID steps.
+    root: Option<String>,
+    /// The steps to evaluate below the root.
+    steps: Vec<(Axis, Step)>,
+}
+
+impl Selector {
+    /// Check whether a target looks like a selector rather than a plain
+    /// scripting ID.
+    pub(crate) fn is_selector(target: &str) -> bool {
+        target.contains("//")
+            || target.split('/').any(|segment| {
+                segment.starts_with("Gui")
+                    || segment.starts_with("type=")
+                    || segment.starts_with('*')
+                    || segment.starts_with('[')
+            })
+    }
+
+    /// Parse a selector.
+    pub(crate) fn parse(selector: &str) -> Result<Self, String> {
+        let mut steps = vec![];
+        for (axis, step) in split_steps(selector)? {
+            steps.push((axis, parse_step(&step)?));
+        }
+
+        // Leading ID steps are looked up directly rather than walked.
+        let absolute = selector.starts_with('/') && !selector.starts_with("//");
+        let literal = steps
+            .iter()
+            .take_while(|(axis, step)| *axis == Axis::Child && matches!(step, Step::Id(_)))
+            .count();
+        let mut root = None;
+        if literal > 0 {
+            let ids: Vec<_> = steps
+                .drain(..literal)
+                .map(|(_, step)| match step {
+                    Step::Id(id) => id,
+                    Step::Test { .. } => unreachable!(),
+                })
+                .collect();
+            let prefix = if absolute { "/" } else { "" };
+            root = Some(format!("{prefix}{}", ids.join("/")));
+        } else if let Some((axis, _)) = steps.first_mut() {
+            // Without a root, search everything below the window.
+            *axis = Axis::Descendant;
+        }
+
+        if steps.is_empty() {
+            return Err(format!("The selector '{selector}' has no component tests."));
+        }
+        Ok(Self { root, steps })
+    }
+
+    /// The scripting ID to evaluate this selector from, if it starts with one.
+    pub(crate) fn root(&self) -> Option<&str> {
+        self.root.as_deref()
+    }
+
+    /// Find every component below `root` that this selector matches.
+    pub(crate) fn select<N: Node>(&self, root: &N) -> Vec<N> {
+        let mut current: Option<Vec<N>> = None;
+        for (axis, step) in &self.steps {
+            let candidates = match &current {
+                None => gather(root, *axis),
+                Some(nodes) => nodes.iter().flat_map(|node| gather(node, *axis)).collect(),
+            };
+
+            let mut seen = vec![];
+            let mut matched = vec![];
+            for node in candidates {
+                let id = node.id();
+                if step.matches(&node) && !seen.contains(&id) {
+                    seen.push(id);
+                    matched.push(node);
+                }
+            }
+            current = Some(matched);
+        }
+        current.unwrap_or_default()
+    }
+}
+
+impl Step {
+    fn matches<N: Node>(&self, node: &N) -> bool {
+        match self {
+            Step::Id(segment) => {
+                let id = node.id();
+                id == *segment || id.ends_with(&format!("/{segment}"))
+            }
+            Step::Test {
+                component_type,
+                predicates,
+            } => {
+                component_type
+                    .as_ref()
+                    .is_none_or(|t| node.attribute("type").is_some_and(|actual| actual == *t))
+                    && predicates.iter().all(|p| p.matches(node))
+            }
+        }
+    }
+}
+
+impl Predicate {
+    fn matches<N: Node>(&self, node: &N) -> bool {
+        let Some(actual) = node.attribute(&self.attribute) else {
+            return false;
+        };
+        match self.operator {
+            Operator::Equals => actual == self.value,
+            Operator::NotEquals => actual != self.value,
+            Operator::Contains => actual.contains(&self.value),
+            Operator::StartsWith => actual.starts_with(&self.value),
+            Operator::EndsWith => actual.ends_with(&self.value),
+        }
+    }
+}
+
+/// The components on an axis from a node.
+fn gather<N: Node>(node: &N, axis: Axis) -> Vec<N> {
+    match axis {
+        Axis::Child => node.children(),
+        Axis::Descendant => {
+            let mut all = vec![];
+            for child in node.children() {
+                let below = gather(&child, Axis::Descendant);
+                all.push(child);
+                all.extend(below);
+            }
+            all
+        }
+    }
+}
+
+/// Split a selector into steps at `/` and `//`, ignoring any inside
+/// predicates.
+fn split_steps(selector: &str) -> Result<Vec<(Axis, String)>, String> {
+    let mut steps = vec![];
+    let mut axis = Axis::Child;
+    let mut current = String::new();
+    let mut in_brackets = false;
+    let mut quote = None;
+    let mut chars = selector.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' | '\'' if in_brackets => {
+                match quote {
+                    None => quote = Some(c),
+                    Some(q) if q == c => quote = None,
+                    Some(_) => (),
+                }
+                current.push(c);
+            }
+            '[' if quote.is_none() => {
+                in_brackets = true;
+                current.push(c);
+            }
+            ']' if quote.is_none() => {
+                in_brackets = false;
+                current.push(c);
+            }
+            '/' if !in_brackets => {
+                let next_axis = if chars.peek() == Some(&'/') {
+                    chars.next();
+                    Axis::Descendant
+                } else {
+                    Axis::Child
+                };
+                if !current.is_empty() {
+                    steps.push((axis, std::mem::take(&mut current)));
+                } else if !steps.is_empty() {
+                    return Err(format!("The selector '{selector}' has an empty step."));
+                }
+                axis = next_axis;
+            }
+            _ => current.push(c),
+        }
+    }
+
+    if quote.is_some() || in_brackets {
+        return Err(format!("The selector '{selector}' isn't closed."));
+    }
+    if current.is_empty() {
+        return Err(format!("The selector '{selector}' ends with a '/'."));
+    }
+    steps.push((axis, current));
+    Ok(steps)
+}
+
+/// Parse a single step.
+fn parse_step(step: &str) -> Result<Step, String> {
+    let test = step.strip_prefix("type=").unwrap_or(step);
+    if !(test.starts_with("Gui") || test.starts_with('*') || test.starts_with('[')) {
+        if step.starts_with("type=") {
+            return Err(format!("'{test}' isn't a component type."));
+        }
+        return Ok(Step::Id(step.to_string()));
+    }
+
+    let (component_type, mut rest) = match test.find('[') {
+        Some(i) => test.split_at(i),
+        None => (test, ""),
+    };
+    let component_type = match component_type {
+        "*" | "" => None,
+        t => Some(t.to_string()),
+    };
+
+    let mut predicates = vec![];
+    while !rest.is_empty() {
+        let (predicate, remaining) = parse_predicate(rest)?;
+        predicates.push(predicate);
+        rest = remaining;
+    }
+
+    Ok(Step::Test {
+        component_type,
+        predicates,
+    })
+}
+
+/// Parse one `[attribute op value]` predicate from the start of `input`,
+/// returning it and the remaining input.
+fn parse_predicate(input: &str) -> Result<(Predicate, &str), String> {
+    let inner = input
+        .strip_prefix('[')
+        .ok_or(format!("Expected '[' at '{input}'."))?;
+
+    let attribute_len = inner
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(inner.len());
+    let (attribute, inner) = inner.split_at(attribute_len);
+    let attribute = attribute.to_ascii_lowercase();
+    if !ATTRIBUTES.contains(&attribute.as_str()) {
+        return Err(format!(
+            "Unknown attribute '{attribute}'. Expected one of: {}",
+            ATTRIBUTES.join(", ")
+        ));
+    }
+
+    let (operator, inner) = [
+        ("!=", Operator::NotEquals),
+        ("~=", Operator::Contains),
+        ("^=", Operator::StartsWith),
+        ("$=", Operator::EndsWith),
+        ("=", Operator::Equals),
+    ]
+    .into_iter()
+    .find_map(|(symbol, operator)| inner.strip_prefix(symbol).map(|rest| (operator, rest)))
+    .ok_or(format!("Expected an operator after '{attribute}'."))?;
+
+    let (value, rest) = if let Some(q @ ('"' | '\'')) = inner.chars().next() {
+        let end = inner[1..]
+            .find(q)
+            .ok_or(format!("Unclosed quote in '{input}'."))?;
+        (&inner[1..=end], &inner[end + 2..])
+    } else {
+        let end = inner
+            .find(']')
+            .ok_or(format!("Expected ']' in '{input}'."))?;
+        (&inner[..end], &inner[end..])
+    };
+    let rest = rest
+        .strip_prefix(']')
+        .ok_or(format!("Expected ']' in '{input}'."))?;
+
+    Ok((
+        Predicate {
+            attribute,
+            operator,
+            value: value.to_string(),
+        },
+        rest,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone)]
+    struct TestNode {
+        id: String,
+        component_type: String,
+        name: String,
+        text: String,
+        tooltip: String,
+        children: Vec<TestNode>,
+    }
+
+    impl TestNode {
+        fn new(id: &str, component_type: &str, name: &str) -> Self {
+            Self {
+                id: id.to_string(),
+                component_type: component_type.to_string(),
+                name: name.to_string(),
+                text: String::new(),
+                tooltip: String::new(),
+                children: vec![],
+            }
+        }
+
+        fn tooltip(mut self, tooltip: &str) -> Self {
+            self.tooltip = tooltip.to_string();
+            self
+        }
+
+        fn text(mut self, text: &str) -> Self {
+            self.text = text.to_string();
+            self
+        }
+
+        fn with(mut self, children: Vec<TestNode>) -> Self {
+            self.children = children;
+            self
+        }
+    }
+
+    impl Node for TestNode {
+        fn id(&self) -> String {
+            self.id.clone()
+        }
+
+        fn attribute(&self, attribute: &str) -> Option<String> {
+            match attribute {
+                "id" => Some(self.id.clone()),
+                "type" => Some(self.component_type.clone()),
+                "name" => Some(self.name.clone()),
+                "text" => Some(self.text.clone()),
+                "tooltip" => Some(self.tooltip.clone()),
+                _ => None,
+            }
+        }
+
+        fn children(&self) -> Vec<Self> {
+            self.children.clone()
+        }
+    }
+
+    /// A main window with a toolbar and a user area holding a subscreen.
+    fn tree() -> TestNode {
+        let w = "/app/con[0]/ses[0]/wnd[0]";
+        TestNode::new(w, "GuiMainWindow", "wnd[0]").with(vec![
+            TestNode::new(&format!("{w}/tbar[0]"), "GuiToolbar", "tbar[0]").with(vec![
+                TestNode::new(&format!("{w}/tbar[0]/btn[11]"), "GuiButton", "btn[11]")
+                    .tooltip("Save   (Ctrl+S)"),
+                TestNode::new(&format!("{w}/tbar[0]/btn[3]"), "GuiButton", "btn[3]")
+                    .tooltip("Back   (F3)"),
+            ]),
+            TestNode::new(&format!("{w}/usr"), "GuiUserArea", "usr").with(vec![
+                TestNode::new(&format!("{w}/usr/lblEKKO-BUKRS"), "GuiLabel", "EKKO-BUKRS")
+                    .text("Company Code"),
+                TestNode::new(
+                    &format!("{w}/usr/subHEADER:SAPLMEGUI:0030"),
+                    "GuiSimpleContainer",
+                    "SAPLMEGUI",
+                )
+                .with(vec![TestNode::new(
+                    &format!("{w}/usr/subHEADER:SAPLMEGUI:0030/ctxtEKKO-BUKRS"),
+                    "GuiCTextField",
+                    "EKKO-BUKRS",
+                )
+                .text("1000")]),
+            ]),
+        ])
+    }
+
+    fn ids(nodes: &[TestNode]) -> Vec<String> {
+        nodes
+            .iter()
+            .map(|n| n.id.rsplit('/').next().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn plain_ids_are_not_selectors() {
+        assert!(!Selector::is_selector("wnd[0]/usr/ctxtRMMG1-MATNR"));
+        assert!(!Selector::is_selector("/app/con[0]/ses[0]/wnd[0]"));
+        assert!(Selector::is_selector("wnd[0]/usr//GuiCTextField"));
+        assert!(Selector::is_selector(r#"type=GuiButton[tooltip~="Save"]"#));
+        assert!(Selector::is_selector("wnd[0]/usr/*[name=BUKRS]"));
+    }
+
+    #[test]
+    fn parses_root_and_steps() {
+        let selector = Selector::parse("wnd[0]/usr//GuiCTextField[name=EKKO-BUKRS]").unwrap();
+        assert_eq!(selector.root(), Some("wnd[0]/usr"));
+        assert_eq!(
+            selector.steps,
+            vec![(
+                Axis::Descendant,
+                Step::Test {
+                    component_type: Some("GuiCTextField".to_string()),
+                    predicates: vec![Predicate {
+                        attribute: "name".to_string(),
+                        operator: Operator::Equals,
+                        value: "EKKO-BUKRS".to_string(),
+                    }],
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn parses_absolute_root() {
+        let selector = Selector::parse("/app/con[0]/ses[0]/wnd[0]//GuiButton").unwrap();
+        assert_eq!(selector.root(), Some("/app/con[0]/ses[0]/wnd[0]"));
+    }
+
+    #[test]
+    fn parses_quoted_values_containing_separators() {
+        let selector = Selector::parse(r#"GuiButton[tooltip='a/b]c'][name^="btn"]"#).unwrap();
+        let Step::Test { predicates, .. } = &selector.steps[0].1 else {
+            panic!("expected a component test");
+        };
+        assert_eq!(predicates[0].value, "a/b]c");
+        assert_eq!(predicates[0].operator, Operator::Equals);
+        assert_eq!(predicates[1].value, "btn");
+        assert_eq!(predicates[1].operator, Operator::StartsWith);
+    }
+
+    #[test]
+    fn rejects_invalid_selectors() {
+        assert!(Selector::parse("GuiButton[colour=red]").is_err());
+        assert!(Selector::parse("GuiButton[name]").is_err());
+        assert!(Selector::parse(r#"GuiButton[name="btn]"#).is_err());
+        assert!(Selector::parse("wnd[0]/usr/").is_err());
+        assert!(Selector::parse("wnd[0]/usr").is_err());
+        assert!(Selector::parse("type=usr").is_err());
+    }
+
+    #[test]
+    fn selector_without_root_searches_descendants() {
+        let selector = Selector::parse(r#"type=GuiButton[tooltip~="Save"]"#).unwrap();
+        assert_eq!(selector.root(), None);
+        assert_eq!(ids(&selector.select(&tree())), vec!["btn[11]"]);
+    }
+
+    #[test]
+    fn child_axis_only_matches_direct_children() {
+        let selector = Selector::parse("usr/GuiCTextField").unwrap();
+        let usr = tree().children[1].clone();
+        assert!(selector.select(&usr).is_empty());
+
+        let selector = Selector::parse("usr//GuiCTextField").unwrap();
+        assert_eq!(ids(&selector.select(&usr)), vec!["ctxtEKKO-BUKRS"]);
+    }
+
+    #[test]
+    fn id_steps_can_follow_tests() {
+        let selector = Selector::parse("//GuiUserArea/lblEKKO-BUKRS").unwrap();
+        assert_eq!(ids(&selector.select(&tree())), vec!["lblEKKO-BUKRS"]);
+    }
+
+    #[test]
+    fn wildcard_with_predicates() {
+        let selector = Selector::parse("*[name=EKKO-BUKRS]").unwrap();
+        assert_eq!(
+            ids(&selector.select(&tree())),
+            vec!["lblEKKO-BUKRS", "ctxtEKKO-BUKRS"]
+        );
+
+        let selector = Selector::parse("*[name=EKKO-BUKRS][type!=GuiLabel]").unwrap();
+        assert_eq!(ids(&selector.select(&tree())), vec!["ctxtEKKO-BUKRS"]);
+
+        let selector = Selector::parse("[text$=Code]").unwrap();
+        assert_eq!(ids(&selector.select(&tree())), vec!["lblEKKO-BUKRS"]);
+    }
+
+    #[test]
+    fn nested_descendant_steps_do_not_duplicate() {
+        let selector = Selector::parse("//*//GuiCTextField").unwrap();
+        assert_eq!(ids(&selector.select(&tree())), vec!["ctxtEKKO-BUKRS"]);
+    }
+}
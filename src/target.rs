@@ -0,0 +1,394 @@
+//! Resolve the targets given to instructions into scripting IDs. As well as
+//! plain IDs (e.g. `wnd[0]/usr/ctxtRMMG1-MATNR`), targets can be written as:
+//!
+//! - `label:<text>`: the input field that goes with the label showing
+//!   `<text>` on the current screen.
+//! - `name:<field>` or `name:<type>:<field>`: the component with the given
+//!   field name, e.g. `name:EKKO-BUKRS` or `name:GuiCTextField:EKKO-BUKRS`,
+//!   wherever it sits on the current screen.
+//! - a selector, e.g. `wnd[0]/usr//GuiCTextField[name=BUKRS]`; see
+//!   [`crate::selector`].
+//! - `repo:<name>`: a logical name from the object repository; see
+//!   [`crate::repository`]. If none of its targets match, its fallback
+//!   attributes are used to find it elsewhere on the screen.
+
+use sap_scripting::{
+    GuiComboBox_Impl, GuiContainer_Impl, GuiSession, GuiSessionInfo_Impl, GuiSession_Impl,
+    GuiTextField_Impl, SAPComponent,
+};
+
+use crate::{
+    component,
+    repository::{Fallback, Repository},
+    selector::{Node, Selector},
+};
+
+/// How far (in pixels, across and down) a component can be from the position
+/// stored in the object repository and still be found by it.
+const POSITION_TOLERANCE: i32 = 20;
+
+/// The result of resolving a target.
+pub(crate) struct Resolved {
+    /// The scripting ID of the component.
+    pub(crate) id: String,
+    /// If the component was only found by a fallback, a description of which
+    /// one, so that the locator can be updated.
+    pub(crate) healed: Option<String>,
+}
+
+/// Resolve a target into a scripting ID.
+pub(crate) fn resolve(
+    session: &GuiSession,
+    repository: &Repository,
+    target: &str,
+) -> Result<Resolved, String> {
+    if let Some(name) = target.strip_prefix("repo:") {
+        return resolve_repository(session, repository, name.trim());
+    }
+    resolve_plain(session, target).map(|id| Resolved { id, healed: None })
+}
+
+/// Resolve a target that doesn't refer to the object repository.
+fn resolve_plain(session: &GuiSession, target: &str) -> Result<String, String> {
+    if let Some(label) = target.strip_prefix("label:") {
+        return resolve_label(session, label.trim());
+    }
+    if let Some(name) = target.strip_prefix("name:") {
+        return resolve_name(session, name.trim());
+    }
+    if Selector::is_selector(target) {
+        return resolve_selector(session, target);
+    }
+    Ok(target.to_string())
+}
+
+/// The types of component searched by a `name:` target that doesn't give one.
+const NAMED_TYPES: [&str; 7] = [
+    "GuiCTextField",
+    "GuiTextField",
+    "GuiPasswordField",
+    "GuiComboBox",
+    "GuiCheckBox",
+    "GuiRadioButton",
+    "GuiButton",
+];
+
+/// Find the single component with a field name in the topmost window,
+/// regardless of the containers it sits in.
+fn resolve_name(session: &GuiSession, name: &str) -> Result<String, String> {
+    let (types, name) = match name.split_once(':') {
+        Some((component_type, name)) if component_type.starts_with("Gui") => {
+            (vec![component_type], name)
+        }
+        _ => (NAMED_TYPES.to_vec(), name),
+    };
+
+    let window_id = component::top_window(session);
+    let window = session
+        .find_by_id(window_id.clone())
+        .map_err(|_| format!("Couldn't find {window_id}."))?;
+    let found: Vec<_> = types
+        .into_iter()
+        .flat_map(|component_type| component::find_all_by_name(&window, name, component_type))
+        .collect();
+
+    match found.as_slice() {
+        [] => Err(format!("There is no field named '{name}' on the screen.")),
+        [comp] => component::id(comp).ok_or(format!("Couldn't get ID of field '{name}'.")),
+        many => Err(format!(
+            "The name '{name}' is ambiguous. Candidates: {}",
+            list_ids(&many.iter().collect::<Vec<_>>())
+        )),
+    }
+}
+
+/// Find the single component a selector matches. Selectors that don't start
+/// with an ID are evaluated against the topmost window.
+fn resolve_selector(session: &GuiSession, selector: &str) -> Result<String, String> {
+    let parsed = Selector::parse(selector)?;
+    let root_id = parsed
+        .root()
+        .map_or_else(|| component::top_window(session), str::to_string);
+    let root = session
+        .find_by_id(root_id.clone())
+        .map_err(|_| format!("Couldn't find {root_id}."))?;
+
+    let matches = parsed.select(&root);
+    match matches.as_slice() {
+        [] => Err(format!("Nothing matches the selector '{selector}'.")),
+        [comp] => component::id(comp).ok_or(format!(
+            "Couldn't get ID of component matching '{selector}'."
+        )),
+        many => Err(format!(
+            "The selector '{selector}' is ambiguous. Candidates: {}",
+            list_ids(&many.iter().collect::<Vec<_>>())
+        )),
+    }
+}
+
+/// Find the first target for a logical name that exists on the screen, or
+/// failing that, the component its fallback attributes match.
+fn resolve_repository(
+    session: &GuiSession,
+    repository: &Repository,
+    name: &str,
+) -> Result<Resolved, String> {
+    let (transaction, screen) = session
+        .info()
+        .map(|info| {
+            (
+                info.transaction().unwrap_or_default(),
+                info.screen_number().unwrap_or_default(),
+            )
+        })
+        .unwrap_or_default();
+    let entry = repository.entry(name, &transaction, screen)?;
+    let exists = |id: &String| session.find_by_id(id.clone()).is_ok();
+
+    if let Some(id) = entry
+        .targets
+        .iter()
+        .filter_map(|target| resolve_plain(session, target).ok())
+        .find(exists)
+    {
+        return Ok(Resolved { id, healed: None });
+    }
+
+    let targets = entry.targets.join(", ");
+    if let Some((description, id)) = heal(session, &entry.fallback) {
+        return Ok(Resolved {
+            healed: Some(format!(
+                "'{name}' wasn't found by its targets ({targets}), but was found by {description} at {id}. Update the object repository to use this ID."
+            )),
+            id,
+        });
+    }
+    Err(format!(
+        "None of the targets for '{name}' are on the screen: {targets}"
+    ))
+}
+
+/// Try each of the fallback attributes that are set, in order, returning a
+/// description of the first that finds a component and its ID.
+fn heal(session: &GuiSession, fallback: &Fallback) -> Option<(String, String)> {
+    let found = |result: Result<String, String>| {
+        result
+            .ok()
+            .filter(|id| session.find_by_id(id.clone()).is_ok())
+    };
+    let component_type = fallback.component_type.as_deref();
+
+    if let Some(name) = &fallback.name {
+        let target = match component_type {
+            Some(component_type) => format!("{component_type}:{name}"),
+            None => name.clone(),
+        };
+        if let Some(id) = found(resolve_name(session, &target)) {
+            return Some((format!("name '{name}'"), id));
+        }
+    }
+    if let Some(label) = &fallback.label {
+        if let Some(id) = found(resolve_label(session, label)) {
+            return Some((format!("label '{label}'"), id));
+        }
+    }
+    if let Some(tooltip) = &fallback.tooltip {
+        if let Some(id) = found(resolve_tooltip(session, component_type, tooltip)) {
+            return Some((format!("tooltip '{tooltip}'"), id));
+        }
+    }
+    if let Some((left, top)) = fallback.position {
+        if let Some(id) = found(resolve_position(session, component_type, left, top)) {
+            return Some((format!("position ({left}, {top})"), id));
+        }
+    }
+    None
+}
+
+/// Find the single component in the topmost window with a tooltip, and of a
+/// type if given.
+fn resolve_tooltip(
+    session: &GuiSession,
+    component_type: Option<&str>,
+    tooltip: &str,
+) -> Result<String, String> {
+    let window_id = component::top_window(session);
+    let window = session
+        .find_by_id(window_id.clone())
+        .map_err(|_| format!("Couldn't find {window_id}."))?;
+    let found: Vec<_> = component::descendants(&window)
+        .into_iter()
+        .filter(|c| {
+            component_type.is_none_or(|t| component::type_name(c).is_some_and(|actual| actual == t))
+                && component::tooltip(c).is_some_and(|actual| actual.trim() == tooltip.trim())
+        })
+        .collect();
+
+    match found.as_slice() {
+        [] => Err(format!(
+            "There is nothing with tooltip '{tooltip}' on the screen."
+        )),
+        [comp] => component::id(comp).ok_or(format!("Couldn't get ID of '{tooltip}'.")),
+        many => Err(format!(
+            "The tooltip '{tooltip}' is ambiguous. Candidates: {}",
+            list_ids(&many.iter().collect::<Vec<_>>())
+        )),
+    }
+}
+
+/// Find the component of a type nearest to a position relative to the
+/// topmost window, within [`POSITION_TOLERANCE`].
+fn resolve_position(
+    session: &GuiSession,
+    component_type: Option<&str>,
+    left: i32,
+    top: i32,
+) -> Result<String, String> {
+    let component_type =
+        component_type.ok_or("A type is needed to find a component by its position.")?;
+    let window_id = component::top_window(session);
+    let window = session
+        .find_by_id(window_id.clone())
+        .map_err(|_| format!("Couldn't find {window_id}."))?;
+    let origin =
+        component::bounds(&window).ok_or(format!("Couldn't get the position of {window_id}."))?;
+
+    component::descendants(&window)
+        .iter()
+        .filter(|c| component::type_name(c).is_some_and(|t| t == component_type))
+        .filter_map(|c| component::bounds(c).map(|b| (c, b)))
+        .map(|(c, b)| {
+            let distance = (b.left - origin.left - left)
+                .abs()
+                .max((b.top - origin.top - top).abs());
+            (c, distance)
+        })
+        .filter(|(_, distance)| *distance <= POSITION_TOLERANCE)
+        .min_by_key(|(_, distance)| *distance)
+        .and_then(|(c, _)| component::id(c))
+        .ok_or(format!(
+            "There is no {component_type} near ({left}, {top}) on the screen."
+        ))
+}
+
+/// Find the input field that goes with a label on the user area of the
+/// topmost window. A field associated with the label is preferred; otherwise
+/// the nearest field to the right of the label on the same row is used.
+fn resolve_label(session: &GuiSession, label: &str) -> Result<String, String> {
+    let root_id = format!("{}/usr", component::top_window(session));
+    let root = session
+        .find_by_id(root_id.clone())
+        .map_err(|_| format!("Couldn't find {root_id}."))?;
+    let all = component::descendants(&root);
+
+    let labels: Vec<_> = all
+        .iter()
+        .filter(|c| {
+            matches!(c, SAPComponent::GuiLabel(_))
+                && component::text(c).is_some_and(|text| label_matches(&text, label))
+        })
+        .collect();
+    let label_comp = match labels.as_slice() {
+        [] => return Err(format!("There is no label '{label}' on the screen.")),
+        [label_comp] => *label_comp,
+        many => {
+            return Err(format!(
+                "The label '{label}' is ambiguous. Candidates: {}",
+                list_ids(many)
+            ))
+        }
+    };
+    let label_id = component::id(label_comp).unwrap_or_default();
+
+    let inputs: Vec<_> = all.iter().filter(|c| is_input(c)).collect();
+
+    let associated: Vec<_> = inputs
+        .iter()
+        .copied()
+        .filter(|c| left_label_id(c).is_some_and(|id| id == label_id))
+        .collect();
+    match associated.as_slice() {
+        [] => (),
+        [field] => {
+            return component::id(field)
+                .ok_or(format!("Couldn't get ID of field for label '{label}'."))
+        }
+        many => {
+            return Err(format!(
+                "The label '{label}' has more than one field. Candidates: {}",
+                list_ids(many)
+            ))
+        }
+    }
+
+    let label_bounds = component::bounds(label_comp)
+        .ok_or(format!("Couldn't get the position of label '{label}'."))?;
+    inputs
+        .iter()
+        .filter_map(|c| component::bounds(c).map(|b| (c, b)))
+        .filter(|(_, b)| label_bounds.same_row(b) && b.left >= label_bounds.left)
+        .min_by_key(|(_, b)| b.left)
+        .and_then(|(c, _)| component::id(c))
+        .ok_or(format!("There is no field next to the label '{label}'."))
+}
+
+/// Compare label text, ignoring case, surrounding space and a trailing colon.
+fn label_matches(text: &str, label: &str) -> bool {
+    let tidy = |s: &str| s.trim().trim_end_matches(':').trim().to_lowercase();
+    tidy(text) == tidy(label)
+}
+
+/// Whether a component is a field that a label can describe.
+fn is_input(comp: &SAPComponent) -> bool {
+    matches!(
+        comp,
+        SAPComponent::GuiTextField(_)
+            | SAPComponent::GuiCTextField(_)
+            | SAPComponent::GuiPasswordField(_)
+            | SAPComponent::GuiComboBox(_)
+            | SAPComponent::GuiCheckBox(_)
+            | SAPComponent::GuiRadioButton(_)
+    )
+}
+
+/// The ID of the label a field declares as its own, if any.
+fn left_label_id(comp: &SAPComponent) -> Option<String> {
+    let label = match comp {
+        SAPComponent::GuiTextField(c) => c.left_label(),
+        SAPComponent::GuiCTextField(c) => c.left_label(),
+        SAPComponent::GuiPasswordField(c) => c.left_label(),
+        SAPComponent::GuiComboBox(c) => c.left_label(),
+        _ => return None,
+    };
+    label.ok().and_then(|label| component::id(&label))
+}
+
+/// List the IDs of components for error messages.
+fn list_ids(comps: &[&SAPComponent]) -> String {
+    comps
+        .iter()
+        .filter_map(|c| component::id(c))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl Node for SAPComponent {
+    fn id(&self) -> String {
+        component::id(self).unwrap_or_default()
+    }
+
+    fn attribute(&self, attribute: &str) -> Option<String> {
+        match attribute {
+            "id" => component::id(self),
+            "type" => component::type_name(self),
+            "name" => component::name(self),
+            "text" => component::text(self),
+            "tooltip" => component::tooltip(self),
+            _ => None,
+        }
+    }
+
+    fn children(&self) -> Vec<Self> {
+        component::children(self)
+    }
+}
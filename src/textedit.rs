@@ -0,0 +1,87 @@
+//! Read and write the text of multi-line text editors (`GuiTextedit`).
+//!
+//! SAP separates lines with `\r`, but text given to and returned from these
+//! functions always uses `\n`, so that it can be compared directly with
+//! multi-line strings from test data. Lines are numbered from 0.
+
+use sap_scripting::{GuiTextedit, GuiTextedit_Impl, GuiVComponent_Impl, SAPComponent};
+
+/// The line ending SAP uses within a text editor.
+const SAP_LINE_ENDING: &str = "\r";
+
+/// Get the text editor a component is, if it is one.
+pub(crate) fn editor(comp: SAPComponent) -> Result<GuiTextedit, String> {
+    match comp {
+        SAPComponent::GuiTextedit(editor) => Ok(editor),
+        _ => Err(String::from(
+            "The target isn't a text editor (GuiTextedit).",
+        )),
+    }
+}
+
+/// Replace `\r\n` and `\r` line endings with `\n`.
+pub(crate) fn normalise(text: &str) -> String {
+    text.replace("\r\n", "\n").replace('\r', "\n")
+}
+
+/// Get the full text of an editor.
+pub(crate) fn get_text(editor: &GuiTextedit) -> Result<String, String> {
+    editor
+        .text()
+        .map(|text| normalise(&text))
+        .map_err(|e| format!("Can't get text: {e}"))
+}
+
+/// Replace the full text of an editor.
+pub(crate) fn set_text(editor: &GuiTextedit, text: &str) -> Result<(), String> {
+    editor
+        .set_text(normalise(text).replace('\n', SAP_LINE_ENDING))
+        .map_err(|e| format!("Can't set text: {e}"))
+}
+
+/// Get the lines of an editor's text.
+pub(crate) fn lines(editor: &GuiTextedit) -> Result<Vec<String>, String> {
+    Ok(get_text(editor)?.split('\n').map(str::to_string).collect())
+}
+
+/// Get a single line of an editor's text.
+pub(crate) fn get_line(editor: &GuiTextedit, line: i32) -> Result<String, String> {
+    let lines = lines(editor)?;
+    let index = line_index(line, lines.len())?;
+    Ok(lines[index].clone())
+}
+
+/// Replace a single line of an editor's text.
+pub(crate) fn set_line(editor: &GuiTextedit, line: i32, text: &str) -> Result<(), String> {
+    let mut lines = lines(editor)?;
+    let index = line_index(line, lines.len())?;
+    lines[index] = text.to_string();
+    set_text(editor, &lines.join("\n"))
+}
+
+/// Add text to the end of an editor's text.
+pub(crate) fn append(editor: &GuiTextedit, text: &str) -> Result<(), String> {
+    let existing = get_text(editor)?;
+    set_text(editor, &format!("{existing}{text}"))
+}
+
+/// Select the characters from `start` up to `end` (counting each line break
+/// as one character).
+pub(crate) fn select(editor: &GuiTextedit, start: i32, end: i32) -> Result<(), String> {
+    if start < 0 || end < start {
+        return Err(format!("{start} to {end} isn't a valid range."));
+    }
+    editor
+        .set_selection_indexes(start, end)
+        .map_err(|e| format!("Can't select text: {e}"))
+}
+
+/// Check a line number against the number of lines.
+fn line_index(line: i32, count: usize) -> Result<usize, String> {
+    usize::try_from(line)
+        .ok()
+        .filter(|index| *index < count)
+        .ok_or(format!(
+            "There is no line {line}. The text has {count} lines, numbered from 0."
+        ))
+}